@@ -0,0 +1,85 @@
+pub const CHANNELS: usize = 2;
+
+fn default_interval() -> f32 { 0.05 }
+fn default_attack() -> f32 { 0.01 }
+fn default_release() -> f32 { 0.2 }
+
+// times in seconds
+#[derive(Copy, Clone, Deserialize, Debug)]
+pub struct AnalysisConfig {
+    #[serde(default = "default_interval")]
+    pub interval: f32, // between sends
+    #[serde(default = "default_attack")]
+    pub attack: f32,
+    #[serde(default = "default_release")]
+    pub release: f32
+}
+
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Levels {
+    pub rms: f32,
+    pub peak: f32,
+    pub envelope: f32
+}
+
+#[derive(Copy, Clone, Default, Debug)]
+struct ChannelLevels {
+    sum_squares: f32,
+    samples: usize,
+    peak: f32,
+    envelope: f32
+}
+
+// one pole coefficient for a time constant
+fn coefficient(time: f32, sample_rate: f32) -> f32 {
+    if time > 0. { (-1. / (time * sample_rate)).exp() } else { 0. }
+}
+
+#[derive(Debug)]
+pub struct AudioAnalyzer {
+    channels: [ChannelLevels; CHANNELS],
+    elapsed: f32 // seconds since the last send
+}
+
+impl AudioAnalyzer {
+    pub fn new() -> Self {
+        AudioAnalyzer {
+            channels: [ChannelLevels::default(); CHANNELS],
+            elapsed: 0.
+        }
+    }
+
+    pub fn process(&mut self, channel: usize, samples: &[f32], config: &AnalysisConfig, sample_rate: f32) {
+        let attack = coefficient(config.attack, sample_rate);
+        let release = coefficient(config.release, sample_rate);
+        let levels = &mut self.channels[channel];
+        for &sample in samples {
+            let level = sample.abs();
+            let coefficient = if level > levels.envelope { attack } else { release };
+            levels.envelope = level + coefficient * (levels.envelope - level);
+            levels.peak = levels.peak.max(level);
+            levels.sum_squares += sample * sample;
+        }
+        levels.samples += samples.len();
+    }
+
+    // levels since the last send, once per interval
+    pub fn take(&mut self, block: f32, interval: f32) -> Option<[Levels; CHANNELS]> {
+        self.elapsed += block;
+        if self.elapsed < interval { return None }
+        self.elapsed = 0.;
+
+        let mut result = [Levels::default(); CHANNELS];
+        for (levels, channel) in result.iter_mut().zip(self.channels.iter_mut()) {
+            *levels = Levels {
+                rms: if channel.samples > 0 { (channel.sum_squares / channel.samples as f32).sqrt() } else { 0. },
+                peak: channel.peak,
+                envelope: channel.envelope
+            };
+            channel.sum_squares = 0.;
+            channel.samples = 0;
+            channel.peak = 0.;
+        }
+        Some(result)
+    }
+}
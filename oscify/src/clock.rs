@@ -0,0 +1,88 @@
+use midi::Message;
+
+const CLOCK: u8 = 0xF8;
+const START: u8 = 0xFA;
+const CONTINUE: u8 = 0xFB;
+const STOP: u8 = 0xFC;
+const SONG_POSITION: u8 = 0xF2;
+
+const TICKS_PER_BEAT: u32 = 24;
+const TICKS_PER_SONG_POSITION: u32 = 6; // song position is in 16th notes
+
+#[derive(Debug, Copy, Clone)]
+pub enum TransportEvent {
+    Playing(bool),
+    Tempo(f32), // bpm
+    Beat(f32)
+}
+
+#[derive(Debug)]
+pub struct MidiClock {
+    running: bool,
+    position: u32, // ticks since the start of the song
+    ticks: u32, // ticks since the last tempo measurement
+    beat_time: Option<f64> // seconds
+}
+
+impl MidiClock {
+    pub fn new() -> Self {
+        MidiClock {
+            running: false,
+            position: 0,
+            ticks: 0,
+            beat_time: None
+        }
+    }
+
+    fn beat(&self) -> TransportEvent {
+        TransportEvent::Beat(self.position as f32 / TICKS_PER_BEAT as f32)
+    }
+
+    // seconds is when the message happened
+    pub fn process_midi_event(&mut self, msg: &Message, seconds: f64) -> Vec<TransportEvent> {
+        match msg {
+            &Message::SysRealTime { status: CLOCK } => {
+                let mut events = vec![];
+                self.ticks += 1;
+                if self.ticks >= TICKS_PER_BEAT || self.beat_time.is_none() {
+                    if let Some(beat_time) = self.beat_time {
+                        let beat_length = seconds - beat_time;
+                        if beat_length > 0. {
+                            events.push(TransportEvent::Tempo((60. / beat_length) as f32));
+                        }
+                    }
+                    self.beat_time = Some(seconds);
+                    self.ticks = 0;
+                }
+                if self.running {
+                    self.position += 1;
+                    if self.position % TICKS_PER_BEAT == 0 {
+                        events.push(self.beat());
+                    }
+                }
+                events
+            },
+            &Message::SysRealTime { status: START } => {
+                self.running = true;
+                self.position = 0;
+                vec![TransportEvent::Playing(true), self.beat()]
+            },
+            &Message::SysRealTime { status: CONTINUE } => {
+                self.running = true;
+                vec![TransportEvent::Playing(true), self.beat()]
+            },
+            &Message::SysRealTime { status: STOP } => {
+                self.running = false;
+                // tempo has to be measured again
+                self.beat_time = None;
+                vec![TransportEvent::Playing(false)]
+            },
+            &Message::SysCommon { status: SONG_POSITION, data: [lsb, msb] } => {
+                let song_position = ((msb as u32) << 7) | (lsb as u32);
+                self.position = song_position * TICKS_PER_SONG_POSITION;
+                vec![self.beat()]
+            },
+            _ => vec![]
+        }
+    }
+}
@@ -4,23 +4,262 @@ use std::error::Error;
 use std::collections::BTreeMap;
 use std::io::{BufReader, Read};
 use std::str::FromStr;
+use std::slice;
 use serde_json;
 
+use scale;
+use scale::{Curve, Scales};
+use tuning;
+use tuning::Tuning;
+use sender::Controller;
+use mpe::Zones;
+use voices::StealPolicy;
+use mono::MonoConfig;
+use smoothing::{RateLimit, Smoothing};
+use analysis::AnalysisConfig;
+use spectrum::SpectrumConfig;
+
 #[derive(Copy, Clone, Deserialize, Debug)]
 pub enum EntryType { Mono, Poly, Drum, Param }
 
+// how per-key pressure is reduced to a single value for Mono entries
+#[derive(Copy, Clone, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PressureReduction { Max, Min, Mean, Last }
+
+impl Default for PressureReduction {
+    fn default() -> Self { PressureReduction::Max }
+}
+
+fn one() -> f32 { 1.0 }
+
+#[derive(Deserialize, Debug)]
+pub struct ControlMapping {
+    pub name: String,
+    #[serde(default)]
+    pub min: f32,
+    #[serde(default = "one")]
+    pub max: f32,
+    #[serde(default)]
+    pub curve: Curve,
+    #[serde(default)]
+    pub toggle: bool
+}
+
+// a CC mapping is either just an address name or a mapping with options
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Control {
+    Name(String),
+    Mapping(ControlMapping)
+}
+
+impl Control {
+    pub fn name(&self) -> &str {
+        match *self {
+            Control::Name(ref name) => name,
+            Control::Mapping(ControlMapping { ref name, .. }) => name
+        }
+    }
+
+    pub fn toggle(&self) -> bool {
+        match *self {
+            Control::Mapping(ControlMapping { toggle, .. }) => toggle,
+            _ => false
+        }
+    }
+
+    // value is in 0..1
+    pub fn apply(&self, value: f32) -> f32 {
+        match *self {
+            Control::Mapping(ControlMapping { min, max, ref curve, .. }) => scale::apply(min, max, curve, value),
+            _ => value
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
-pub struct JsonEntry<T: Ord> {
+pub struct ParamMapping {
+    pub name: String,
+    #[serde(default)]
+    pub min: f32,
+    #[serde(default = "one")]
+    pub max: f32,
+    pub default: Option<f32>, // between min and max
+    #[serde(default)]
+    pub unit: String,
+    pub steps: Option<u32>, // number of distinct values
+    #[serde(default)]
+    pub curve: Curve,
+    #[serde(default)]
+    pub interval: f32, // seconds
+    #[serde(default)]
+    pub threshold: f32, // in 0..1
+    #[serde(default)]
+    pub smoothing: Smoothing
+}
+
+// a user param is either just an address name or a mapping with metadata
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Parameter {
+    Name(String),
+    Mapping(ParamMapping)
+}
+
+impl Parameter {
+    pub fn name(&self) -> &str {
+        match *self {
+            Parameter::Name(ref name) => name,
+            Parameter::Mapping(ParamMapping { ref name, .. }) => name
+        }
+    }
+
+    pub fn unit(&self) -> &str {
+        match *self {
+            Parameter::Mapping(ParamMapping { ref unit, .. }) => unit,
+            _ => ""
+        }
+    }
+
+    pub fn rate_limit(&self) -> RateLimit {
+        match *self {
+            Parameter::Mapping(ParamMapping { interval, threshold, smoothing, .. }) =>
+                RateLimit { interval, threshold, smoothing },
+            _ => RateLimit::default()
+        }
+    }
+
+    // host value in 0..1
+    pub fn default_value(&self) -> f32 {
+        match *self {
+            Parameter::Mapping(ParamMapping { default: Some(default), .. }) => self.normalize(default),
+            _ => 0.
+        }
+    }
+
+    // value is in 0..1
+    pub fn apply(&self, value: f32) -> Option<f32> {
+        match *self {
+            Parameter::Mapping(ParamMapping { min, max, ref curve, steps, .. }) => {
+                let value = match steps {
+                    Some(steps) if steps > 1 => {
+                        let last = (steps - 1) as f32;
+                        (value * last).round() / last
+                    },
+                    _ => value
+                };
+                Some(scale::apply(min, max, curve, value))
+            },
+            _ => None
+        }
+    }
+
+    // output value back to 0..1
+    pub fn normalize(&self, value: f32) -> f32 {
+        match *self {
+            Parameter::Mapping(ParamMapping { min, max, ref curve, .. }) =>
+                scale::invert(min, max, curve, value).max(0.).min(1.),
+            _ => value
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum ManufacturerId {
+    Short(u8),
+    Extended([u8; 3])
+}
+
+impl ManufacturerId {
+    fn as_slice(&self) -> &[u8] {
+        match *self {
+            ManufacturerId::Short(ref id) => slice::from_ref(id),
+            ManufacturerId::Extended(ref id) => id
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SysExConfig {
+    pub address: String,
+    #[serde(default)]
+    pub manufacturers: Vec<ManufacturerId> // forwards everything when empty
+}
+
+impl SysExConfig {
+    pub fn accepts(&self, manufacturer: &[u8]) -> bool {
+        self.manufacturers.is_empty() ||
+            self.manufacturers.iter().any(|id| id.as_slice() == manufacturer)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JsonEntry<T: Ord, U: Ord> {
     #[serde(rename = "type")]
     pub entry_type: EntryType,
     pub name: String,
     pub address: String,
     #[serde(default)]
-    pub params: Vec<String>,
-    pub keys: Option<BTreeMap<T, String>>
+    pub params: Vec<Parameter>,
+    pub keys: Option<BTreeMap<T, String>>,
+    pub cc: Option<BTreeMap<T, Control>>,
+    pub cc14: Option<BTreeMap<T, Control>>, // keyed by the MSB controller
+    pub nrpn: Option<BTreeMap<U, Control>>,
+    #[serde(default)]
+    pub key_pressure: PressureReduction,
+    pub mpe: Option<Zones>,
+    pub voices: Option<usize>, // allocates voice ids instead of using the channel
+    #[serde(default)]
+    pub steal: StealPolicy,
+    #[serde(default)]
+    pub mono: MonoConfig,
+    #[serde(default)]
+    pub pedals: bool, // forward sustain and sostenuto
+    pub sysex: Option<SysExConfig>,
+    pub analysis: Option<AnalysisConfig>, // audio levels
+    pub spectrum: Option<SpectrumConfig>, // fft bands
+    #[serde(default)]
+    pub scale: Scales,
+    #[serde(default, deserialize_with = "tuning::deserialize")]
+    pub tuning: Option<Tuning> // scala files, 12-TET without one
 }
 
-pub type Entry = JsonEntry<u8>;
+pub type Entry = JsonEntry<u8, u16>;
+
+impl Entry {
+    pub fn control(&self, controller: Controller) -> Option<&Control> {
+        let (map, key) = match controller {
+            Controller::Cc(cc) => (self.cc.as_ref(), cc),
+            Controller::Cc14(cc) => (self.cc14.as_ref(), cc),
+            Controller::Nrpn(param) =>
+                return self.nrpn.as_ref().and_then(|nrpn| nrpn.get(&param))
+        };
+        map.and_then(|map| map.get(&key))
+    }
+
+    // outgoing value of a user param, value is in 0..1
+    pub fn param_value(&self, index: usize, value: f32) -> f32 {
+        match self.params.get(index).and_then(|param| param.apply(value)) {
+            Some(value) => value,
+            None => self.scale.params.as_ref().map_or(value, |scale| scale.apply(value))
+        }
+    }
+
+    // inverse of param_value
+    pub fn param_normalize(&self, index: usize, value: f32) -> f32 {
+        match self.params.get(index) {
+            Some(param @ &Parameter::Mapping(_)) => param.normalize(value),
+            _ => self.scale.params.as_ref().map_or(value, |scale| scale.invert(value))
+        }
+    }
+
+    // whether a CC is one half of a configured 14 bit pair
+    pub fn is_high_res(&self, controller: u8) -> bool {
+        controller < 64 && self.control(Controller::Cc14(controller & 0x1F)).is_some()
+    }
+}
 
 pub fn load() -> Result<Vec<Entry>, Box<Error>> {
     let mut path = env::current_dir()?;
@@ -34,28 +273,52 @@ pub fn load() -> Result<Vec<Entry>, Box<Error>> {
 
     file.read_to_string(&mut s)?;
 
-    let config: Vec<JsonEntry<String>> = serde_json::from_str(&s)?;
+    parse(&s)
+}
+
+pub fn parse(s: &str) -> Result<Vec<Entry>, Box<Error>> {
+    let config: Vec<JsonEntry<String, String>> = serde_json::from_str(s)?;
     let config: Result<Vec<_>, _> = config.into_iter().map(try_from).collect();
     let config = config?;
 
     Ok(config)
 }
 
-fn try_from(entry: JsonEntry<String>) -> Result<Entry, Box<Error>> {
-    let mut next_entry = JsonEntry {
-        entry_type: entry.entry_type,
-        name: entry.name,
-        address: entry.address,
-        params: entry.params,
-        keys: None
-    };
-    if let Some(map) = entry.keys {
+fn parse_keys<K, V>(map: Option<BTreeMap<String, V>>) -> Result<Option<BTreeMap<K, V>>, Box<Error>>
+    where K: FromStr + Ord, K::Err: Error + 'static
+{
+    if let Some(map) = map {
         let mut next_map = BTreeMap::new();
         for (key, value) in map {
-            let key = u8::from_str(&key)?;
+            let key = K::from_str(&key)?;
             next_map.insert(key, value);
         }
-        next_entry.keys = Some(next_map);
+        Ok(Some(next_map))
+    } else {
+        Ok(None)
     }
-    Ok(next_entry)
+}
+
+fn try_from(entry: JsonEntry<String, String>) -> Result<Entry, Box<Error>> {
+    Ok(JsonEntry {
+        entry_type: entry.entry_type,
+        name: entry.name,
+        address: entry.address,
+        params: entry.params,
+        keys: parse_keys(entry.keys)?,
+        cc: parse_keys(entry.cc)?,
+        cc14: parse_keys(entry.cc14)?,
+        nrpn: parse_keys(entry.nrpn)?,
+        key_pressure: entry.key_pressure,
+        mpe: entry.mpe,
+        voices: entry.voices,
+        steal: entry.steal,
+        mono: entry.mono,
+        pedals: entry.pedals,
+        sysex: entry.sysex,
+        analysis: entry.analysis,
+        spectrum: entry.spectrum,
+        scale: entry.scale,
+        tuning: entry.tuning
+    })
 }
@@ -0,0 +1,191 @@
+use midi::Message;
+
+const NRPN: (u8, u8) = (99, 98);
+const RPN: (u8, u8) = (101, 100);
+const DATA_ENTRY: (u8, u8) = (6, 38);
+
+const NRPN_MSB: u8 = NRPN.0;
+const NRPN_LSB: u8 = NRPN.1;
+const RPN_MSB: u8 = RPN.0;
+const RPN_LSB: u8 = RPN.1;
+const DATA_ENTRY_MSB: u8 = DATA_ENTRY.0;
+const DATA_ENTRY_LSB: u8 = DATA_ENTRY.1;
+const DATA_INCREMENT: u8 = 96;
+const DATA_DECREMENT: u8 = 97;
+
+pub const RPN_PITCH_BEND_RANGE: u16 = 0;
+pub const RPN_FINE_TUNING: u16 = 1;
+pub const RPN_COARSE_TUNING: u16 = 2;
+pub const RPN_MPE_CONFIGURATION: u16 = 6;
+
+// CC 0-31 are paired with CC 32-63 for 14 bit resolution
+const LSB_OFFSET: u8 = 32;
+const NULL_PARAM: (u8, u8) = (0x7F, 0x7F);
+const U14_MAX: u16 = (1 << 14) - 1;
+
+fn to_u14((msb, lsb): (u8, u8)) -> u16 {
+    ((msb as u16) << 7) | (lsb as u16)
+}
+
+pub fn u14_into_f32(x: u16) -> f32 {
+    x as f32 / (U14_MAX as f32)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Param {
+    Rpn(u16),
+    Nrpn(u16)
+}
+
+impl Param {
+    // how much a data increment or decrement changes the value
+    fn step(&self) -> u16 {
+        match *self {
+            Param::Rpn(RPN_COARSE_TUNING) | Param::Rpn(RPN_MPE_CONFIGURATION) => 1 << 7,
+            _ => 1
+        }
+    }
+
+    // the value a param has before any data entry
+    fn default_value(&self) -> u16 {
+        match *self {
+            Param::Rpn(RPN_PITCH_BEND_RANGE) => to_u14((2, 0)),
+            Param::Rpn(RPN_FINE_TUNING) | Param::Rpn(RPN_COARSE_TUNING) => to_u14((64, 0)),
+            _ => 0
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum ControllerEvent {
+    HighRes {
+        channel: u8,
+        controller: u8,
+        value: u16
+    },
+    Param {
+        channel: u8,
+        param: Param,
+        value: u16
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Selected {
+    Rpn((u8, u8)),
+    Nrpn((u8, u8))
+}
+
+impl Selected {
+    fn param(&self) -> Option<Param> {
+        match *self {
+            Selected::Rpn(NULL_PARAM) | Selected::Nrpn(NULL_PARAM) => None,
+            Selected::Rpn(param) => Some(Param::Rpn(to_u14(param))),
+            Selected::Nrpn(param) => Some(Param::Nrpn(to_u14(param)))
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ChannelState {
+    msb: [u8; LSB_OFFSET as usize],
+    selected: Selected,
+    values: Vec<(Param, u16)>
+}
+
+impl ChannelState {
+    fn new() -> Self {
+        ChannelState {
+            msb: [0; LSB_OFFSET as usize],
+            selected: Selected::Rpn(NULL_PARAM),
+            values: vec![]
+        }
+    }
+
+    fn get_value(&self, param: Param) -> u16 {
+        self.values.iter()
+            .find(|&&(p, _)| p == param)
+            .map(|&(_, value)| value)
+            .unwrap_or_else(|| param.default_value())
+    }
+
+    fn set_value(&mut self, param: Param, value: u16) {
+        self.values.retain(|&(p, _)| p != param);
+        self.values.push((param, value));
+    }
+
+    // applies a data entry change to the selected param
+    fn update_value<F: Fn(u16, u16) -> u16>(&mut self, f: F) -> Option<(Param, u16)> {
+        self.selected.param().map(|param| {
+            let value = f(self.get_value(param), param.step());
+            self.set_value(param, value);
+            (param, value)
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Controllers {
+    channels: Vec<ChannelState> // index is channel
+}
+
+impl Controllers {
+    pub fn new() -> Self {
+        Controllers {
+            channels: (0..16).map(|_| ChannelState::new()).collect()
+        }
+    }
+
+    pub fn reset(&mut self, channel: u8) {
+        self.channels[channel as usize].selected = Selected::Rpn(NULL_PARAM);
+    }
+
+    pub fn process_midi_event(&mut self, msg: &Message) -> Option<ControllerEvent> {
+        let (channel, controller, value) = match msg {
+            &Message::ControlChange { channel, controller, value } => (channel, controller, value),
+            _ => return None
+        };
+        let state = &mut self.channels[channel as usize];
+        let param_value = match controller {
+            RPN_MSB | RPN_LSB | NRPN_MSB | NRPN_LSB => {
+                state.selected = match (controller, state.selected) {
+                    (RPN_MSB, Selected::Rpn((_, lsb))) => Selected::Rpn((value, lsb)),
+                    (RPN_MSB, _) => Selected::Rpn((value, 0)),
+                    (RPN_LSB, Selected::Rpn((msb, _))) => Selected::Rpn((msb, value)),
+                    (RPN_LSB, _) => Selected::Rpn((0, value)),
+                    (NRPN_MSB, Selected::Nrpn((_, lsb))) => Selected::Nrpn((value, lsb)),
+                    (NRPN_MSB, _) => Selected::Nrpn((value, 0)),
+                    (NRPN_LSB, Selected::Nrpn((msb, _))) => Selected::Nrpn((msb, value)),
+                    _ => Selected::Nrpn((0, value))
+                };
+                None
+            },
+            DATA_ENTRY_MSB =>
+                state.update_value(|_, _| to_u14((value, 0))),
+            DATA_ENTRY_LSB =>
+                state.update_value(|x, _| (x & !0x7F) | (value as u16)),
+            DATA_INCREMENT =>
+                state.update_value(|x, step| (x + step).min(U14_MAX)),
+            DATA_DECREMENT =>
+                state.update_value(|x, step| x.saturating_sub(step)),
+            0...31 => {
+                state.msb[controller as usize] = value;
+                return Some(ControllerEvent::HighRes {
+                    channel,
+                    controller,
+                    value: to_u14((value, 0))
+                })
+            },
+            32...63 => {
+                let controller = controller - LSB_OFFSET;
+                return Some(ControllerEvent::HighRes {
+                    channel,
+                    controller,
+                    value: to_u14((state.msb[controller as usize], value))
+                })
+            },
+            _ => None
+        };
+        param_value.map(|(param, value)| ControllerEvent::Param { channel, param, value })
+    }
+}
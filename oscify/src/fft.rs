@@ -0,0 +1,63 @@
+use std::f32::consts::PI;
+
+// in place radix-2 fft, tables are computed up front so transforms don't allocate
+#[derive(Debug)]
+pub struct Fft {
+    size: usize,
+    bit_reverse: Vec<usize>,
+    cos: Vec<f32>,
+    sin: Vec<f32>
+}
+
+impl Fft {
+    // size must be a power of two
+    pub fn new(size: usize) -> Self {
+        let bits = size.trailing_zeros();
+        let bit_reverse = (0..size).map(|i| {
+            (0..bits).fold(0, |reversed, bit| (reversed << 1) | ((i >> bit) & 1))
+        }).collect();
+        let twiddles = size / 2;
+        Fft {
+            size,
+            bit_reverse,
+            cos: (0..twiddles).map(|i| (2. * PI * i as f32 / size as f32).cos()).collect(),
+            sin: (0..twiddles).map(|i| -(2. * PI * i as f32 / size as f32).sin()).collect()
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn process(&self, re: &mut [f32], im: &mut [f32]) {
+        let n = self.size;
+        for i in 0..n {
+            let j = self.bit_reverse[i];
+            if j > i {
+                re.swap(i, j);
+                im.swap(i, j);
+            }
+        }
+
+        let mut len = 2;
+        while len <= n {
+            let half = len / 2;
+            let stride = n / len;
+            let mut start = 0;
+            while start < n {
+                for k in 0..half {
+                    let (w_re, w_im) = (self.cos[k * stride], self.sin[k * stride]);
+                    let (a, b) = (start + k, start + k + half);
+                    let t_re = re[b] * w_re - im[b] * w_im;
+                    let t_im = re[b] * w_im + im[b] * w_re;
+                    re[b] = re[a] - t_re;
+                    im[b] = im[a] - t_im;
+                    re[a] += t_re;
+                    im[a] += t_im;
+                }
+                start += len;
+            }
+            len *= 2;
+        }
+    }
+}
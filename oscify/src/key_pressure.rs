@@ -0,0 +1,62 @@
+use midi::Message;
+use config::PressureReduction;
+
+#[derive(Debug)]
+pub struct KeyPressure {
+    held: Vec<(u8, u8, u8)> // (channel, key, pressure), most recently touched last
+}
+
+impl KeyPressure {
+    pub fn new() -> Self {
+        KeyPressure {
+            held: vec![]
+        }
+    }
+
+    // keys that aren't held are ignored
+    pub fn set_pressure(&mut self, channel: u8, key: u8, pressure: u8) {
+        if self.held.iter().any(|&(c, k, _)| c == channel && k == key) {
+            self.hold(channel, key, pressure);
+        }
+    }
+
+    fn hold(&mut self, channel: u8, key: u8, pressure: u8) {
+        self.release(channel, key);
+        self.held.push((channel, key, pressure));
+    }
+
+    pub fn reset(&mut self, channel: u8) {
+        for held in self.held.iter_mut().filter(|held| held.0 == channel) {
+            held.2 = 0;
+        }
+    }
+
+    pub fn reduce(&self, reduction: PressureReduction) -> Option<u8> {
+        let pressures = self.held.iter().map(|&(_, _, pressure)| pressure);
+        match reduction {
+            PressureReduction::Max => pressures.max(),
+            PressureReduction::Min => pressures.min(),
+            PressureReduction::Mean => {
+                let count = self.held.len() as u32;
+                if count == 0 { return None }
+                let sum: u32 = pressures.map(|p| p as u32).sum();
+                Some(((sum + count / 2) / count) as u8)
+            },
+            PressureReduction::Last => pressures.last()
+        }
+    }
+
+    fn release(&mut self, channel: u8, key: u8) {
+        self.held.retain(|&(c, k, _)| c != channel || k != key);
+    }
+
+    pub fn process_midi_event(&mut self, msg: &Message) {
+        match msg {
+            &Message::NoteOff { channel, key, .. } =>
+                self.release(channel, key),
+            &Message::NoteOn { channel, key, .. } =>
+                self.hold(channel, key, 0),
+            _ => ()
+        }
+    }
+}
@@ -13,10 +13,25 @@ extern crate rosc;
 
 mod logger;
 mod config;
+mod scale;
 mod osc;
 mod midi;
 mod vst;
 mod sender;
 mod midi_pitch;
+mod mpe;
+mod key_pressure;
+mod controllers;
+mod voices;
+mod mono;
+mod pedals;
+mod clock;
+mod tuning;
+mod mts;
+mod state;
+mod smoothing;
+mod analysis;
+mod fft;
+mod spectrum;
 
 plugin_main!(vst::OscifyPlugin);
@@ -1,5 +1,9 @@
 use self::Message::*;
 
+const DEFAULT_RELEASE_VELOCITY: u8 = 0x40;
+pub const SYSEX_START: u8 = 0xF0;
+pub const SYSEX_END: u8 = 0xF7;
+
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum Message {
 
@@ -49,8 +53,7 @@ pub enum Message {
     },
 
     SysEx {
-        id: u8,
-        data: Vec<u8>
+        data: Vec<u8> // the whole message, from 0xF0 to 0xF7
     },
 
     SysCommon {
@@ -75,6 +78,12 @@ impl Message {
                 key: data[1],
                 velocity: data[2]
             },
+            // running status keyboards send note offs as note ons with no velocity
+            0x90 if data[2] == 0 => NoteOff {
+                channel,
+                key: data[1],
+                velocity: DEFAULT_RELEASE_VELOCITY
+            },
             0x90 => NoteOn {
                 channel,
                 key: data[1],
@@ -110,10 +119,9 @@ impl Message {
                 channel,
                 value: (data[2] as u16) << 7 | (data[1] as u16)
             },
-            0xF0 => match data[0] & 0xF8 {
+            0xF0 => match data[0] {
                 0xF0 => SysEx {
-                    id: data[1],
-                    data: data[2..].to_vec()
+                    data: data.to_vec()
                 },
                 0xF1 | 0xF3 => SysCommon {
                     status: data[0],
@@ -137,3 +145,12 @@ impl Message {
         Ok(message)
     }
 }
+
+// manufacturer ids are one byte, or three bytes starting with 0
+pub fn sysex_manufacturer(data: &[u8]) -> &[u8] {
+    match data.get(1) {
+        Some(&0) => &data[1..data.len().min(4)],
+        Some(_) => &data[1..2],
+        None => &[]
+    }
+}
@@ -1,95 +1,159 @@
 use midi::Message;
+use controllers::{RPN_PITCH_BEND_RANGE, RPN_FINE_TUNING, RPN_COARSE_TUNING, RPN_MPE_CONFIGURATION};
+use mpe::{Zone, Zones};
+use tuning::Tuning;
+use mts;
+use mts::TuningChange;
 
-const RPN: (u8, u8) = (101, 100);
-const DATA_ENTRY: (u8, u8) = (6, 38);
+const MPE_MEMBER_BEND_RANGE: f32 = 48.;
+const MPE_MASTER_BEND_RANGE: f32 = 2.;
 
-const RPN_MSB: u8 = RPN.0;
-const RPN_LSB: u8 = RPN.1;
-const DATA_ENTRY_MSB: u8 = DATA_ENTRY.0;
-const DATA_ENTRY_LSB: u8 = DATA_ENTRY.1;
-
-const PITCH_BEND: (u8, u8) = (0, 0);
-
-fn to_u14((msb, lsb): (u8, u8)) -> u16 {
-    ((msb as u16) << 7) | (lsb as u16)
+#[derive(Debug, Copy, Clone)]
+struct ChannelState {
+    key: Option<u8>,
+    bend: u16,
+    bend_range: f32, // semitones
+    fine_tune: f32, // semitones
+    coarse_tune: f32, // semitones
+    octave_tune: [f32; 12] // semitones, index is pitch class
 }
 
-#[derive(Debug, Copy, Clone)]
-enum NoteStatus {
-    Off,
-    On(u8),
-    PendingBend(f32)
+impl ChannelState {
+    fn new() -> Self {
+        ChannelState {
+            key: None,
+            bend: 1 << 13, // centered
+            bend_range: 2.,
+            fine_tune: 0.,
+            coarse_tune: 0.,
+            octave_tune: [0.; 12]
+        }
+    }
+
+    fn get_bend(&self) -> f32 {
+        let bend_amount = (self.bend as f32) / ((1 << 14) as f32);
+        (bend_amount * 2. - 1.) * self.bend_range
+    }
 }
 
 #[derive(Debug)]
 pub struct MidiPitch {
-    selected_param: (u8, u8),
-    pitch_bend: (u8, u8),
-    keys: [NoteStatus; 16] // index is channel
+    channels: [ChannelState; 16], // index is channel
+    pub zones: Zones,
+    tuning: Tuning
 }
 
 impl MidiPitch {
     pub fn new() -> Self {
         MidiPitch {
-            selected_param: (0x7F, 0x7F),
-            pitch_bend: (2, 0), // 2 semitones
-            keys: [NoteStatus::Off; 16]
+            channels: [ChannelState::new(); 16],
+            zones: Zones::default(),
+            tuning: Tuning::default()
         }
     }
 
     pub fn get_key(&self, channel: u8) -> u8 {
-        match self.keys[channel as usize] {
-            NoteStatus::On(key) => key,
-            _ => 0
+        self.get_note(channel).unwrap_or(0)
+    }
+
+    pub fn get_note(&self, channel: u8) -> Option<u8> {
+        self.channels[channel as usize].key
+    }
+
+    pub fn get_pitch(&self, channel: u8, key: u8) -> f32 {
+        let state = &self.channels[channel as usize];
+        // master channel bend applies to every note in the zone
+        let master_bend = self.zones.member_zone(channel)
+            .map(|zone| self.channels[zone.master() as usize].get_bend())
+            .unwrap_or(0.);
+
+        // unmapped keys fall back to 12-TET
+        let note = self.tuning.get_note(key).unwrap_or(key as f32);
+
+        note + state.octave_tune[(key % 12) as usize] + state.coarse_tune + state.fine_tune + state.get_bend() + master_bend
+    }
+
+    pub fn set_tuning(&mut self, tuning: Tuning) {
+        self.tuning = tuning;
+    }
+
+    pub fn set_bend(&mut self, channel: u8, value: u16) {
+        self.channels[channel as usize].bend = value;
+    }
+
+    // resets expression, but keeps tuning and bend range
+    pub fn reset_controllers(&mut self, channel: u8) {
+        self.channels[channel as usize].bend = ChannelState::new().bend;
+    }
+
+    pub fn configure_mpe(&mut self, zone: Zone, members: u8) {
+        self.zones.configure(zone, members);
+        // a zone with no members is off
+        if members == 0 { return }
+
+        self.channels[zone.master() as usize].bend_range = MPE_MASTER_BEND_RANGE;
+        for member in self.zones.member_channels(zone) {
+            self.channels[member as usize].bend_range = MPE_MEMBER_BEND_RANGE;
+        }
+    }
+
+    // turns both zones off and restores the default bend ranges
+    pub fn reset_mpe(&mut self) {
+        self.zones = Zones::default();
+        for state in self.channels.iter_mut() {
+            state.bend_range = ChannelState::new().bend_range;
         }
     }
 
-    pub fn get_pitch(&mut self, channel: u8, pitch_bend_amount: u16) -> Option<f32> {
-        let channel = channel as usize;
-        let pitch_bend = (to_u14(self.pitch_bend) as f32) / ((1 << 7) as f32);
-        let pitch_bend_amount = (pitch_bend_amount as f32) / ((1 << 14) as f32);
-        let bend = (pitch_bend_amount * 2. - 1.) * pitch_bend;
-
-        match self.keys[channel] {
-            NoteStatus::On(key) => Some((key as f32) + bend),
-            _ => {
-                self.keys[channel] = NoteStatus::PendingBend(bend);
-                None
-            }
+    pub fn process_rpn(&mut self, channel: u8, param: u16, value: u16) {
+        let (msb, lsb) = ((value >> 7) as u8, (value & 0x7F) as u8);
+        let state = &mut self.channels[channel as usize];
+        match param {
+            RPN_PITCH_BEND_RANGE =>
+                state.bend_range = (msb as f32) + (lsb as f32) / 100.,
+            RPN_FINE_TUNING =>
+                state.fine_tune = ((value as f32) - ((1 << 13) as f32)) / ((1 << 13) as f32),
+            RPN_COARSE_TUNING =>
+                state.coarse_tune = (msb as f32) - 64.,
+            RPN_MPE_CONFIGURATION => match Zone::from_master(channel) {
+                Some(zone) => self.configure_mpe(zone, msb),
+                None => info!("MPE configuration on non-master channel {}", channel)
+            },
+            _ => info!("Unknown param: {:?}", param)
         }
     }
 
-    pub fn get_pending_pitch(&self, channel: u8, key: u8) -> Option<f32> {
-        match self.keys[channel as usize] {
-            NoteStatus::PendingBend(bend) => Some((key as f32) + bend),
-            _ => None
+    // returns whether the message changed the tuning
+    pub fn process_sysex(&mut self, data: &[u8]) -> bool {
+        match mts::parse(data) {
+            Some(TuningChange::Notes(notes)) => {
+                for (key, note) in notes {
+                    self.tuning.set_note(key, note)
+                }
+                true
+            },
+            Some(TuningChange::Octave { channels, offsets }) => {
+                for channel in 0..16 {
+                    if channels & (1 << channel) != 0 {
+                        self.channels[channel].octave_tune = offsets
+                    }
+                }
+                true
+            },
+            None => false
         }
     }
 
     pub fn process_midi_event(&mut self, msg: &Message) {
         match msg {
-            &Message::NoteOff { channel, .. } =>
-                self.keys[channel as usize] = NoteStatus::Off,
+            &Message::NoteOff { channel, key, .. } => {
+                let state = &mut self.channels[channel as usize];
+                if state.key == Some(key) {
+                    state.key = None
+                }
+            },
             &Message::NoteOn { channel, key, .. } =>
-                self.keys[channel as usize] = NoteStatus::On(key),
-            &Message::ControlChange { controller, value, .. } =>
-                match controller {
-                    RPN_MSB =>
-                        self.selected_param = (value, self.selected_param.1),
-                    RPN_LSB =>
-                        self.selected_param = (self.selected_param.0, value),
-                    DATA_ENTRY_MSB =>
-                        match self.selected_param {
-                            PITCH_BEND => self.pitch_bend = (value, self.pitch_bend.1),
-                            _ => info!("Unknown param: {:?}", self.selected_param)
-                        },
-                    DATA_ENTRY_LSB =>
-                        match self.selected_param {
-                            PITCH_BEND => self.pitch_bend = (self.pitch_bend.0, value),
-                            _ => info!("Unknown param: {:?}", self.selected_param)
-                        },
-                    _ => ()
-                },
+                self.channels[channel as usize].key = Some(key),
             _ => ()
         }
     }
@@ -0,0 +1,62 @@
+#[derive(Copy, Clone, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority { Last, Low, High }
+
+impl Default for Priority {
+    fn default() -> Self { Priority::Last }
+}
+
+#[derive(Copy, Clone, Default, Deserialize, Debug)]
+pub struct MonoConfig {
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub legato: bool, // don't retrigger when moving between held notes
+    #[serde(default)]
+    pub glide: f32 // seconds
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Note {
+    pub channel: u8,
+    pub key: u8,
+    pub velocity: u8
+}
+
+#[derive(Debug)]
+pub struct NoteStack {
+    notes: Vec<Note> // held notes, oldest first
+}
+
+impl NoteStack {
+    pub fn new() -> Self {
+        NoteStack {
+            notes: vec![]
+        }
+    }
+
+    pub fn push(&mut self, note: Note) {
+        self.remove(note.channel, note.key);
+        self.notes.push(note);
+    }
+
+    pub fn remove(&mut self, channel: u8, key: u8) {
+        self.notes.retain(|note| note.channel != channel || note.key != key);
+    }
+
+    pub fn channel_notes(&self, channel: u8) -> Vec<Note> {
+        self.notes.iter()
+            .filter(|note| note.channel == channel)
+            .cloned()
+            .collect()
+    }
+
+    // the note that should be sounding
+    pub fn current(&self, priority: Priority) -> Option<Note> {
+        match priority {
+            Priority::Last => self.notes.last(),
+            Priority::Low => self.notes.iter().rev().min_by_key(|note| note.key),
+            Priority::High => self.notes.iter().max_by_key(|note| note.key)
+        }.cloned()
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::ops::Range;
+
+const LOWER_ZONE_MASTER: u8 = 0;
+const UPPER_ZONE_MASTER: u8 = 15;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Zone { Lower, Upper }
+
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Zone::Lower => write!(f, "lower"),
+            Zone::Upper => write!(f, "upper")
+        }
+    }
+}
+
+impl Zone {
+    pub fn master(&self) -> u8 {
+        match *self {
+            Zone::Lower => LOWER_ZONE_MASTER,
+            Zone::Upper => UPPER_ZONE_MASTER
+        }
+    }
+
+    pub fn from_master(channel: u8) -> Option<Zone> {
+        match channel {
+            LOWER_ZONE_MASTER => Some(Zone::Lower),
+            UPPER_ZONE_MASTER => Some(Zone::Upper),
+            _ => None
+        }
+    }
+}
+
+// number of member channels in each zone, 0 means the zone is off
+#[derive(Debug, Copy, Clone, Default, Deserialize)]
+pub struct Zones {
+    #[serde(default)]
+    pub lower: u8,
+    #[serde(default)]
+    pub upper: u8
+}
+
+impl Zones {
+    // a new zone shrinks the other one if they overlap,
+    // both masters and all members have to fit in 16 channels,
+    // turning a zone off leaves the other one as it is
+    pub fn configure(&mut self, zone: Zone, members: u8) {
+        let members = members.min(15);
+        let other = |other: u8| if members > 0 { other.min(14u8.saturating_sub(members)) } else { other };
+        match zone {
+            Zone::Lower => {
+                self.lower = members;
+                self.upper = other(self.upper);
+            },
+            Zone::Upper => {
+                self.upper = members;
+                self.lower = other(self.lower);
+            }
+        }
+    }
+
+    fn members(&self, zone: Zone) -> u8 {
+        match zone {
+            Zone::Lower => self.lower,
+            Zone::Upper => self.upper
+        }
+    }
+
+    pub fn channels(&self, zone: Zone) -> Range<u8> {
+        match zone {
+            Zone::Lower => LOWER_ZONE_MASTER..(LOWER_ZONE_MASTER + 1 + self.lower),
+            Zone::Upper => (UPPER_ZONE_MASTER - self.upper)..(UPPER_ZONE_MASTER + 1)
+        }
+    }
+
+    pub fn member_channels(&self, zone: Zone) -> Range<u8> {
+        match zone {
+            Zone::Lower => (LOWER_ZONE_MASTER + 1)..(LOWER_ZONE_MASTER + 1 + self.lower),
+            Zone::Upper => (UPPER_ZONE_MASTER - self.upper)..UPPER_ZONE_MASTER
+        }
+    }
+
+    // the zone a channel is the master of, if that zone is on
+    pub fn master_zone(&self, channel: u8) -> Option<Zone> {
+        Zone::from_master(channel).and_then(|zone|
+            if self.members(zone) > 0 { Some(zone) } else { None })
+    }
+
+    pub fn member_zone(&self, channel: u8) -> Option<Zone> {
+        [Zone::Lower, Zone::Upper].iter()
+            .find(|&&zone| self.member_channels(zone).any(|c| c == channel))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zone_off_keeps_other_zone() {
+        let mut zones = Zones::default();
+        zones.configure(Zone::Lower, 15);
+        zones.configure(Zone::Upper, 0);
+        assert_eq!((zones.lower, zones.upper), (15, 0));
+        assert_eq!(zones.member_channels(Zone::Lower), 1..16);
+        assert_eq!(zones.member_zone(15), Some(Zone::Lower));
+    }
+
+    #[test]
+    fn overlapping_zone_shrinks_other() {
+        let mut zones = Zones::default();
+        zones.configure(Zone::Lower, 10);
+        zones.configure(Zone::Upper, 8);
+        assert_eq!((zones.lower, zones.upper), (6, 8));
+        assert_eq!(zones.channels(Zone::Lower), 0..7);
+        assert_eq!(zones.channels(Zone::Upper), 7..16);
+
+        // a full zone leaves no room for the other one
+        zones.configure(Zone::Lower, 15);
+        assert_eq!((zones.lower, zones.upper), (15, 0));
+    }
+}
@@ -0,0 +1,130 @@
+// MIDI Tuning Standard sysex
+
+const UNIVERSAL_NON_REAL_TIME: u8 = 0x7E;
+const UNIVERSAL_REAL_TIME: u8 = 0x7F;
+// the plugin has no device id of its own
+const ALL_CALL: u8 = 0x7F;
+const MIDI_TUNING: u8 = 0x08;
+
+const BULK_DUMP: u8 = 0x01;
+const NOTE_CHANGE: u8 = 0x02;
+const BANK_NOTE_CHANGE: u8 = 0x07;
+const OCTAVE_1_BYTE: u8 = 0x08;
+const OCTAVE_2_BYTE: u8 = 0x09;
+
+const BULK_DUMP_NAME_LEN: usize = 16;
+const NO_CHANGE: [u8; 3] = [0x7F, 0x7F, 0x7F];
+
+#[derive(Debug)]
+pub enum TuningChange {
+    Notes(Vec<(u8, f32)>), // (key, fractional midi note)
+    Octave { channels: u16, offsets: [f32; 12] } // semitones per pitch class, bit mask of channels
+}
+
+// xx yy zz: semitone and 14 bit fraction of it
+fn frequency(data: &[u8]) -> Option<f32> {
+    if data == NO_CHANGE { return None }
+    let fraction = ((data[1] as u16) << 7) | (data[2] as u16);
+    Some((data[0] as f32) + (fraction as f32) / ((1 << 14) as f32))
+}
+
+fn note_changes(data: &[u8]) -> Option<TuningChange> {
+    let count = match data.first() {
+        Some(&count) => count as usize,
+        None => return None
+    };
+    let changes = data[1..].chunks(4).take(count)
+        .filter(|change| change.len() == 4)
+        .filter_map(|change| frequency(&change[1..]).map(|note| (change[0], note)))
+        .collect();
+    Some(TuningChange::Notes(changes))
+}
+
+fn bulk_dump(data: &[u8]) -> Option<TuningChange> {
+    let notes = match data.get(BULK_DUMP_NAME_LEN..BULK_DUMP_NAME_LEN + 128 * 3) {
+        Some(notes) => notes,
+        None => return None
+    };
+    let changes = notes.chunks(3).enumerate()
+        .filter_map(|(key, data)| frequency(data).map(|note| (key as u8, note)))
+        .collect();
+    Some(TuningChange::Notes(changes))
+}
+
+fn octave(data: &[u8], two_byte: bool) -> Option<TuningChange> {
+    let value_len = if two_byte { 2 } else { 1 };
+    if data.len() < 3 + 12 * value_len { return None }
+
+    let channels = ((data[0] as u16) << 14) | ((data[1] as u16) << 7) | (data[2] as u16);
+    let mut offsets = [0.; 12];
+    for (offset, value) in offsets.iter_mut().zip(data[3..].chunks(value_len)) {
+        *offset = if two_byte {
+            // 0x2000 is centered, +-100 cents
+            let value = ((value[0] as u16) << 7) | (value[1] as u16);
+            ((value as f32) - ((1 << 13) as f32)) / ((1 << 13) as f32)
+        } else {
+            // 0x40 is centered, in cents
+            ((value[0] as f32) - 64.) / 100.
+        };
+    }
+    Some(TuningChange::Octave { channels, offsets })
+}
+
+// data is the whole message, including F0 and F7
+pub fn parse(data: &[u8]) -> Option<TuningChange> {
+    if data.len() < 6 { return None }
+    let (universal, device, sub_id, format) = (data[1], data[2], data[3], data[4]);
+    if universal != UNIVERSAL_NON_REAL_TIME && universal != UNIVERSAL_REAL_TIME { return None }
+    // messages for other devices don't retune this one
+    if device != ALL_CALL { return None }
+    if sub_id != MIDI_TUNING { return None }
+
+    // the tuning program and bank are ignored, changes apply to the active tuning
+    let body = &data[5..data.len() - 1];
+    match format {
+        BULK_DUMP => body.get(1..).and_then(bulk_dump),
+        NOTE_CHANGE => body.get(1..).and_then(note_changes),
+        BANK_NOTE_CHANGE => body.get(2..).and_then(note_changes),
+        OCTAVE_1_BYTE => octave(body, false),
+        OCTAVE_2_BYTE => octave(body, true),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tuning::Tuning;
+
+    // real time single note change of key 69 to 69.5
+    fn note_change(device: u8, key: u8) -> Vec<u8> {
+        vec![0xF0, UNIVERSAL_REAL_TIME, device, MIDI_TUNING, NOTE_CHANGE, 0, 1, key, 69, 0x40, 0, 0xF7]
+    }
+
+    #[test]
+    fn all_call_note_change() {
+        match parse(&note_change(ALL_CALL, 69)) {
+            Some(TuningChange::Notes(notes)) => assert_eq!(notes, vec![(69, 69.5)]),
+            change => panic!("unexpected {:?}", change)
+        }
+    }
+
+    #[test]
+    fn other_devices_are_ignored() {
+        assert!(parse(&note_change(0x01, 69)).is_none());
+    }
+
+    #[test]
+    fn keys_past_127_are_ignored() {
+        let notes = match parse(&note_change(ALL_CALL, 0xC5)) {
+            Some(TuningChange::Notes(notes)) => notes,
+            change => panic!("unexpected {:?}", change)
+        };
+        let mut tuning = Tuning::default();
+        for (key, note) in notes {
+            tuning.set_note(key, note);
+        }
+        assert_eq!(tuning.get_note(0xC5), None);
+        assert_eq!(tuning.get_note(69), Some(69.));
+    }
+}
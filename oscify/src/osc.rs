@@ -9,6 +9,10 @@ use rosc::{encoder, OscPacket, OscMessage, OscType, OscBundle};
 const TO_PORT: u16 = 9001;
 const BASE_HOST_PORT: u16 = 9100;
 
+pub fn default_destination() -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), TO_PORT)
+}
+
 #[derive(Debug)]
 pub struct OscSender {
     pub id: u16,
@@ -19,8 +23,8 @@ pub struct OscSender {
 
 impl OscSender {
     pub fn new() -> Result<Self, String> {
-        let home_ip: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
-        let to_addr = SocketAddrV4::new(home_ip, TO_PORT);
+        let to_addr = default_destination();
+        let home_ip = *to_addr.ip();
         let mut id = 0;
         let sock = (0..100).flat_map(|i| {
             id = i;
@@ -35,19 +39,46 @@ impl OscSender {
         }
     }
 
+    // keeps the current port when the id is taken
+    pub fn rebind(&mut self, id: u16) -> Result<(), Box<Error>> {
+        if id == self.id { return Ok(()) }
+        let host_addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), BASE_HOST_PORT + id);
+        self.sock = UdpSocket::bind(host_addr)?;
+        self.id = id;
+        Ok(())
+    }
+
+    pub fn destination(&self) -> SocketAddrV4 {
+        self.to_addr
+    }
+
+    pub fn set_destination(&mut self, to_addr: SocketAddrV4) {
+        self.to_addr = to_addr;
+    }
+
     pub fn push(&mut self, addr: String, arg: OscType, t: (u32, u32)) {
+        self.push_args(addr, vec![ arg ], t);
+    }
+
+    pub fn push_args(&mut self, addr: String, args: Vec<OscType>, t: (u32, u32)) {
         self.queue.push(OscPacket::Bundle(OscBundle {
             timetag: OscType::Time(t.0, t.1),
             content: vec![
                 OscPacket::Message(OscMessage {
                     addr,
-                    args: Some(vec![ arg ])
+                    args: Some(args)
                 })
             ]
         }));
     }
 
     pub fn flush(&mut self) -> Result<(), Box<Error>> {
+        if self.queue.is_empty() { return Ok(()) }
+        // in time order, packets without a time go first
+        self.queue.sort_by_key(|packet| match *packet {
+            OscPacket::Bundle(OscBundle { timetag: OscType::Time(sec, frac), .. }) => (sec, frac),
+            _ => (0, 0)
+        });
         let packet = OscPacket::Bundle(OscBundle {
             timetag: OscType::Time(0, 0),
             content: self.queue.drain(..).collect()
@@ -72,3 +103,56 @@ impl Error for OscError {
         "OSC error"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use rosc::decoder;
+
+    fn received(sock: &UdpSocket) -> Vec<(String, OscType)> {
+        let mut buf = [0u8; 4096];
+        let size = sock.recv(&mut buf).unwrap();
+        match decoder::decode(&buf[..size]).unwrap() {
+            OscPacket::Bundle(bundle) => bundle.content.into_iter().map(|packet| match packet {
+                OscPacket::Bundle(OscBundle { timetag, content }) => match content.into_iter().next() {
+                    Some(OscPacket::Message(msg)) => (msg.addr, timetag),
+                    _ => panic!("empty bundle")
+                },
+                OscPacket::Message(msg) => (msg.addr, OscType::Nil)
+            }).collect(),
+            _ => panic!("expected a bundle")
+        }
+    }
+
+    #[test]
+    fn flush_sends_in_time_order() {
+        let mut sender = OscSender::new().unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        if let SocketAddr::V4(addr) = sock.local_addr().unwrap() {
+            sender.set_destination(addr);
+        }
+
+        // params are pushed after the block's midi, with the block start time
+        sender.push("/note/a".to_string(), OscType::Bool(true), (10, 300));
+        sender.push("/note/b".to_string(), OscType::Bool(true), (11, 0));
+        sender.push("/note/c".to_string(), OscType::Bool(false), (10, 100));
+        sender.push("/param/x".to_string(), OscType::Float(0.5), (10, 0));
+        sender.push("/param/y".to_string(), OscType::Float(0.5), (10, 300));
+        sender.flush().unwrap();
+
+        assert_eq!(received(&sock), vec![
+            ("/param/x".to_string(), OscType::Time(10, 0)),
+            ("/note/c".to_string(), OscType::Time(10, 100)),
+            // equal times keep the order they were pushed in
+            ("/note/a".to_string(), OscType::Time(10, 300)),
+            ("/param/y".to_string(), OscType::Time(10, 300)),
+            ("/note/b".to_string(), OscType::Time(11, 0))
+        ]);
+        // the queue is empty after a flush
+        sender.flush().unwrap();
+        assert!(sender.queue.is_empty());
+    }
+}
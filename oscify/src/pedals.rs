@@ -0,0 +1,200 @@
+use std::fmt;
+
+const CC_SUSTAIN: u8 = 64;
+const CC_SOSTENUTO: u8 = 66;
+
+#[derive(Debug, Copy, Clone)]
+pub enum Pedal { Sustain, Sostenuto }
+
+impl fmt::Display for Pedal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Pedal::Sustain => write!(f, "sustain"),
+            Pedal::Sostenuto => write!(f, "sostenuto")
+        }
+    }
+}
+
+impl Pedal {
+    pub fn from_controller(controller: u8) -> Option<Pedal> {
+        match controller {
+            CC_SUSTAIN => Some(Pedal::Sustain),
+            CC_SOSTENUTO => Some(Pedal::Sostenuto),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ChannelPedals {
+    sustain: bool,
+    sostenuto: bool,
+    held: Vec<u8>, // keys that are physically down
+    sostenuto_keys: Vec<u8>, // keys that were down when sostenuto was pressed
+    deferred: Vec<(u8, u8)> // (key, release velocity) of released keys that are still sounding
+}
+
+impl ChannelPedals {
+    fn new() -> Self {
+        ChannelPedals {
+            sustain: false,
+            sostenuto: false,
+            held: vec![],
+            sostenuto_keys: vec![],
+            deferred: vec![]
+        }
+    }
+
+    fn is_sustained(&self, key: u8) -> bool {
+        self.sustain || (self.sostenuto && self.sostenuto_keys.contains(&key))
+    }
+
+    // releases the deferred notes the pedals no longer hold
+    fn release(&mut self) -> Vec<(u8, u8)> {
+        let (sustained, released) = self.deferred.iter()
+            .partition(|&&(key, _)| self.is_sustained(key));
+        self.deferred = sustained;
+        released
+    }
+}
+
+#[derive(Debug)]
+pub struct Pedals {
+    channels: Vec<ChannelPedals> // index is channel
+}
+
+impl Pedals {
+    pub fn new() -> Self {
+        Pedals {
+            channels: (0..16).map(|_| ChannelPedals::new()).collect()
+        }
+    }
+
+    pub fn note_on(&mut self, channel: u8, key: u8) {
+        let state = &mut self.channels[channel as usize];
+        // striking a sustained key again ends its deferred release
+        state.deferred.retain(|&(k, _)| k != key);
+        state.held.retain(|&k| k != key);
+        state.held.push(key);
+    }
+
+    // whether a note off should wait for the pedals to be released
+    pub fn defer_note_off(&mut self, channel: u8, key: u8, velocity: u8) -> bool {
+        let state = &mut self.channels[channel as usize];
+        state.held.retain(|&k| k != key);
+        if state.is_sustained(key) {
+            state.deferred.retain(|&(k, _)| k != key);
+            state.deferred.push((key, velocity));
+            true
+        } else {
+            false
+        }
+    }
+
+    // lifts both pedals, returning the notes to release
+    pub fn reset(&mut self, channel: u8) -> Vec<(u8, u8)> {
+        let state = &mut self.channels[channel as usize];
+        state.sustain = false;
+        state.sostenuto = false;
+        state.sostenuto_keys.clear();
+        state.release()
+    }
+
+    // returns the (key, release velocity) of notes to release now
+    pub fn set_pedal(&mut self, channel: u8, pedal: Pedal, down: bool) -> Vec<(u8, u8)> {
+        let state = &mut self.channels[channel as usize];
+        match pedal {
+            Pedal::Sustain => state.sustain = down,
+            Pedal::Sostenuto => {
+                if down && !state.sostenuto {
+                    state.sostenuto_keys = state.held.clone();
+                } else if !down {
+                    state.sostenuto_keys.clear();
+                }
+                state.sostenuto = down;
+            }
+        }
+        state.release()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sustain_defers_note_off() {
+        let mut pedals = Pedals::new();
+        pedals.note_on(0, 60);
+        assert!(pedals.set_pedal(0, Pedal::Sustain, true).is_empty());
+        assert!(pedals.defer_note_off(0, 60, 10));
+        assert_eq!(pedals.set_pedal(0, Pedal::Sustain, false), vec![(60, 10)]);
+        // nothing is left to release
+        assert!(pedals.set_pedal(0, Pedal::Sustain, false).is_empty());
+    }
+
+    #[test]
+    fn sustain_is_per_channel() {
+        let mut pedals = Pedals::new();
+        pedals.set_pedal(0, Pedal::Sustain, true);
+        pedals.note_on(1, 60);
+        assert!(!pedals.defer_note_off(1, 60, 0));
+    }
+
+    #[test]
+    fn sostenuto_holds_keys_down_when_pressed() {
+        let mut pedals = Pedals::new();
+        pedals.note_on(0, 60);
+        pedals.set_pedal(0, Pedal::Sostenuto, true);
+        pedals.note_on(0, 64);
+        assert!(!pedals.defer_note_off(0, 64, 0));
+        assert!(pedals.defer_note_off(0, 60, 20));
+        assert_eq!(pedals.set_pedal(0, Pedal::Sostenuto, false), vec![(60, 20)]);
+    }
+
+    #[test]
+    fn sostenuto_ignores_keys_released_before_it() {
+        let mut pedals = Pedals::new();
+        pedals.note_on(0, 60);
+        assert!(!pedals.defer_note_off(0, 60, 0));
+        pedals.set_pedal(0, Pedal::Sostenuto, true);
+        pedals.note_on(0, 60);
+        assert!(!pedals.defer_note_off(0, 60, 0));
+    }
+
+    #[test]
+    fn restrike_ends_deferred_release() {
+        let mut pedals = Pedals::new();
+        pedals.set_pedal(0, Pedal::Sustain, true);
+        pedals.note_on(0, 60);
+        assert!(pedals.defer_note_off(0, 60, 0));
+        pedals.note_on(0, 60);
+        assert!(pedals.set_pedal(0, Pedal::Sustain, false).is_empty());
+        assert!(!pedals.defer_note_off(0, 60, 30));
+    }
+
+    #[test]
+    fn restruck_key_is_deferred_once() {
+        let mut pedals = Pedals::new();
+        pedals.set_pedal(0, Pedal::Sustain, true);
+        pedals.note_on(0, 60);
+        pedals.defer_note_off(0, 60, 0);
+        pedals.note_on(0, 60);
+        pedals.defer_note_off(0, 60, 40);
+        assert_eq!(pedals.set_pedal(0, Pedal::Sustain, false), vec![(60, 40)]);
+    }
+
+    #[test]
+    fn reset_lifts_both_pedals() {
+        let mut pedals = Pedals::new();
+        pedals.note_on(0, 60);
+        pedals.note_on(0, 64);
+        pedals.set_pedal(0, Pedal::Sostenuto, true);
+        pedals.set_pedal(0, Pedal::Sustain, true);
+        pedals.defer_note_off(0, 60, 1);
+        pedals.defer_note_off(0, 64, 2);
+        assert_eq!(pedals.reset(0), vec![(60, 1), (64, 2)]);
+        pedals.note_on(0, 60);
+        assert!(!pedals.defer_note_off(0, 60, 0));
+    }
+}
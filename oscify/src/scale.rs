@@ -0,0 +1,177 @@
+// steepness of the exp and log curves
+const CURVE_K: f32 = 4.0;
+
+const A4_KEY: f32 = 69.;
+const MAX_KEY: f32 = 127.;
+
+// quietest level sent in db, silence would be -inf
+const MIN_DB: f32 = -96.;
+
+fn default_max() -> f32 { 1.0 }
+fn default_a4() -> f32 { 440.0 }
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Curve {
+    Linear,
+    Exp,
+    Log,
+    Table(Vec<(f32, f32)>) // (input, output) breakpoints, sorted by input
+}
+
+impl Default for Curve {
+    fn default() -> Self { Curve::Linear }
+}
+
+impl Curve {
+    // x is in 0..1, output is in 0..1 except for tables
+    pub fn apply(&self, x: f32) -> f32 {
+        let k = CURVE_K.exp2() - 1.0;
+        match *self {
+            Curve::Linear => x,
+            Curve::Exp => ((CURVE_K * x).exp2() - 1.0) / k,
+            Curve::Log => (1.0 + x * k).log2() / CURVE_K,
+            Curve::Table(ref points) => interpolate(points, x)
+        }
+    }
+
+    // inverse of apply, tables need to be increasing
+    pub fn invert(&self, y: f32) -> f32 {
+        let k = CURVE_K.exp2() - 1.0;
+        match *self {
+            Curve::Linear => y,
+            Curve::Exp => (1.0 + y * k).log2() / CURVE_K,
+            Curve::Log => ((CURVE_K * y).exp2() - 1.0) / k,
+            Curve::Table(ref points) => {
+                let swapped: Vec<_> = points.iter().map(|&(x, y)| (y, x)).collect();
+                interpolate(&swapped, y)
+            }
+        }
+    }
+}
+
+// linear interpolation between breakpoints, clamped to the end points
+fn interpolate(points: &[(f32, f32)], x: f32) -> f32 {
+    match points.iter().position(|&(px, _)| px >= x) {
+        None => points.last().map_or(x, |&(_, y)| y),
+        Some(0) => points[0].1,
+        Some(i) => {
+            let (x0, y0) = points[i - 1];
+            let (x1, y1) = points[i];
+            y0 + (x - x0) / (x1 - x0) * (y1 - y0)
+        }
+    }
+}
+
+pub fn apply(min: f32, max: f32, curve: &Curve, x: f32) -> f32 {
+    min + curve.apply(x) * (max - min)
+}
+
+pub fn invert(min: f32, max: f32, curve: &Curve, y: f32) -> f32 {
+    if max == min { return 0. }
+    curve.invert((y - min) / (max - min))
+}
+
+#[derive(Copy, Clone, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    Linear,
+    Db // the scaled value is an amplitude, sent in decibels
+}
+
+impl Default for Unit {
+    fn default() -> Self { Unit::Linear }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct Scale {
+    #[serde(default)]
+    pub min: f32,
+    #[serde(default = "default_max")]
+    pub max: f32,
+    #[serde(default)]
+    pub curve: Curve,
+    #[serde(default)]
+    pub unit: Unit
+}
+
+impl Scale {
+    pub fn apply(&self, x: f32) -> f32 {
+        let y = apply(self.min, self.max, &self.curve, x);
+        match self.unit {
+            Unit::Linear => y,
+            Unit::Db => (20. * y.log10()).max(MIN_DB)
+        }
+    }
+
+    pub fn invert(&self, y: f32) -> f32 {
+        let y = match self.unit {
+            Unit::Linear => y,
+            Unit::Db if y <= MIN_DB => 0.,
+            Unit::Db => 10f32.powf(y / 20.)
+        };
+        invert(self.min, self.max, &self.curve, y)
+    }
+}
+
+#[derive(Copy, Clone, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PitchUnit { Note, Hz, Cents }
+
+impl Default for PitchUnit {
+    fn default() -> Self { PitchUnit::Note }
+}
+
+// min and max remap the unit's range over midi keys 0..127, through the curve
+#[derive(Clone, Deserialize, Debug)]
+pub struct PitchScale {
+    #[serde(default)]
+    pub unit: PitchUnit,
+    #[serde(default = "default_a4")]
+    pub a4: f32, // hz
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    #[serde(default)]
+    pub curve: Curve
+}
+
+impl Default for PitchScale {
+    fn default() -> Self {
+        PitchScale { unit: PitchUnit::Note, a4: default_a4(), min: None, max: None, curve: Curve::Linear }
+    }
+}
+
+impl PitchScale {
+    fn in_unit(&self, pitch: f32) -> f32 {
+        match self.unit {
+            PitchUnit::Note => pitch,
+            PitchUnit::Hz => self.a4 * ((pitch - A4_KEY) / 12.).exp2(),
+            PitchUnit::Cents => pitch * 100.
+        }
+    }
+
+    // pitch is a fractional midi note
+    pub fn apply(&self, pitch: f32) -> f32 {
+        let value = self.in_unit(pitch);
+        match (self.min, self.max, &self.curve) {
+            (None, None, &Curve::Linear) => value,
+            (min, max, curve) => {
+                let (low, high) = (self.in_unit(0.), self.in_unit(MAX_KEY));
+                let x = (value - low) / (high - low);
+                apply(min.unwrap_or(low), max.unwrap_or(high), curve, x)
+            }
+        }
+    }
+}
+
+// scaling for each output of an entry, outputs without one are sent as they are
+#[derive(Clone, Default, Deserialize, Debug)]
+pub struct Scales {
+    pub velocity: Option<Scale>,
+    pub pressure: Option<Scale>,
+    pub timbre: Option<Scale>,
+    pub pan: Option<Scale>,
+    pub params: Option<Scale>,
+    #[serde(default)]
+    pub pitch: PitchScale
+}
@@ -4,14 +4,27 @@ use rosc::OscType;
 
 use config;
 use config::EntryType;
+use mpe::{Zone, Zones};
+use voices::VoiceAllocator;
+use pedals::Pedal;
+use clock::TransportEvent;
+use analysis::Levels;
+use spectrum::SpectrumFormat;
+use scale::Scale;
 use vst::OscifyPlugin;
 
 #[derive(Debug, Copy, Clone)]
-pub struct NoteMessage {
-    pub note_on: bool,
-    pub channel: u8,
-    pub key: u8,
-    pub velocity: f32
+pub enum NoteMessage {
+    On {
+        channel: u8,
+        key: u8,
+        velocity: f32
+    },
+    Off {
+        channel: u8,
+        key: u8,
+        release_velocity: f32
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -22,6 +35,29 @@ pub struct ChannelMessage {
     pub value: f32
 }
 
+#[derive(Debug, Copy, Clone)]
+pub enum Controller {
+    Cc(u8),
+    Cc14(u8),
+    Nrpn(u16)
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ControlMessage {
+    pub controller: Controller,
+    pub channel: u8,
+    pub key: u8,
+    pub value: f32 // normalized to 0..1
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct PedalMessage {
+    pub pedal: Pedal,
+    pub channel: u8,
+    pub key: u8,
+    pub down: bool
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct ParamMessage {
     pub param_index: usize,
@@ -50,6 +86,8 @@ enum AddressNode<'a> {
     U8(u8),
     Usize(usize),
     Ch(ChannelType),
+    Zone(Zone),
+    Pedal(Pedal),
     None,
     DoNotSend
 }
@@ -68,6 +106,9 @@ impl<'a> From<u8> for AddressNode<'a> {
 impl<'a> From<ChannelType> for AddressNode<'a> {
     fn from(x: ChannelType) -> Self { AddressNode::Ch(x) }
 }
+impl<'a> From<Pedal> for AddressNode<'a> {
+    fn from(x: Pedal) -> Self { AddressNode::Pedal(x) }
+}
 
 impl<'a> fmt::Display for AddressNode<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -77,6 +118,8 @@ impl<'a> fmt::Display for AddressNode<'a> {
             AddressNode::Usize(x) => write!(f, "/{}", x),
             AddressNode::U8(x) => write!(f, "/{}", x),
             AddressNode::Ch(x) => write!(f, "/{}", x),
+            AddressNode::Zone(x) => write!(f, "/zone/{}", x),
+            AddressNode::Pedal(x) => write!(f, "/{}", x),
             _ => Ok(())
         }
     }
@@ -96,11 +139,18 @@ impl<'a> AddressNode<'a> {
             .unwrap_or_else(|| entry_index.into())
     }
 
-    fn id(entry: Option<&config::Entry>, (channel, key): (u8, u8)) -> AddressNode {
+    fn id<'b>(entry: Option<&'b config::Entry>, zones: &Zones, voices: Option<&VoiceAllocator>, (channel, key): (u8, u8)) -> AddressNode<'b> {
         let entry_type = entry.map(|e| e.entry_type).unwrap_or(EntryType::Mono);
         match entry_type {
             EntryType::Mono => AddressNode::None,
-            EntryType::Poly => channel.into(),
+            EntryType::Poly => match (voices, zones.master_zone(channel)) {
+                (Some(voices), _) => voices.find(channel, key)
+                    .map(|id| id.into())
+                    .unwrap_or(AddressNode::DoNotSend),
+                // MPE master channel messages apply to the whole zone
+                (None, Some(zone)) => AddressNode::Zone(zone),
+                (None, None) => channel.into()
+            },
             EntryType::Drum => match entry {
                 Some(&config::Entry { keys: Some(ref keys), .. }) =>
                     keys.get(&key)
@@ -115,46 +165,104 @@ impl<'a> AddressNode<'a> {
     fn param(entry: Option<&config::Entry>, param_index: usize) -> AddressNode {
         entry
             .and_then(|e| e.params.get(param_index))
-            .map(|param_name| param_name[..].into())
+            .map(|param| param.name().into())
             .unwrap_or_else(|| param_index.into())
     }
 }
 
+fn scaled(scale: Option<&Scale>, value: f32) -> f32 {
+    scale.map_or(value, |scale| scale.apply(value))
+}
+
 const NS_NODE: &str = "/oscify";
 const NOTE_NODE: &str = "/note";
 const PARAM_NODE: &str = "/param";
+const PANIC_NODE: &str = "/panic";
+const PROGRAM_NODE: &str = "/program";
+const TRANSPORT_NODE: &str = "/transport";
+const AUDIO_NODE: &str = "/audio";
+const SPECTRUM_NODE: &str = "/spectrum";
 impl OscifyPlugin {
-    pub fn send_note(&mut self, NoteMessage { note_on, channel, key, velocity }: NoteMessage, t: (u32, u32)) {
+    pub fn send_note(&mut self, msg: NoteMessage, t: (u32, u32)) {
         let entry = self.entries.get(self.entry_index);
 
-        let id_address_node = AddressNode::id(entry, (channel, key));
+        let (channel, key) = match msg {
+            NoteMessage::On { channel, key, .. } | NoteMessage::Off { channel, key, .. } => (channel, key)
+        };
+
+        let id_address_node = AddressNode::id(entry, &self.midi_pitch.zones, self.voices.as_ref(), (channel, key));
+        if !id_address_node.should_send() { return }
+
+        let base_address_node = AddressNode::base(entry, self.entry_index);
+
+        match msg {
+            NoteMessage::On { velocity, .. } => {
+                let velocity = scaled(entry.and_then(|e| e.scale.velocity.as_ref()), velocity);
+                self.osc_sender.push(
+                    format!("{}{}{}{}/on", NS_NODE, base_address_node, id_address_node, NOTE_NODE),
+                    OscType::Bool(true),
+                    t
+                );
+                self.osc_sender.push(
+                    format!("{}{}{}{}/key", NS_NODE, base_address_node, id_address_node, NOTE_NODE),
+                    OscType::Int(key.into()),
+                    t
+                );
+                self.osc_sender.push(
+                    format!("{}{}{}{}/vel", NS_NODE, base_address_node, id_address_node, NOTE_NODE),
+                    OscType::Float(velocity),
+                    t
+                );
+                self.osc_sender.push(
+                    format!("{}{}{}{}/phase", NS_NODE, base_address_node, id_address_node, NOTE_NODE),
+                    OscType::Float(self.phase),
+                    t
+                );
+            },
+            NoteMessage::Off { release_velocity, .. } => {
+                let release_velocity = scaled(entry.and_then(|e| e.scale.velocity.as_ref()), release_velocity);
+                self.osc_sender.push(
+                    format!("{}{}{}{}/on", NS_NODE, base_address_node, id_address_node, NOTE_NODE),
+                    OscType::Bool(false),
+                    t
+                );
+                self.osc_sender.push(
+                    format!("{}{}{}{}/rel", NS_NODE, base_address_node, id_address_node, NOTE_NODE),
+                    OscType::Float(release_velocity),
+                    t
+                );
+            }
+        }
+    }
+
+    pub fn send_key(&mut self, channel: u8, key: u8, t: (u32, u32)) {
+        let entry = self.entries.get(self.entry_index);
+
+        let id_address_node = AddressNode::id(entry, &self.midi_pitch.zones, self.voices.as_ref(), (channel, key));
         if !id_address_node.should_send() { return }
 
         let base_address_node = AddressNode::base(entry, self.entry_index);
 
         self.osc_sender.push(
-            format!("{}{}{}{}/on", NS_NODE, base_address_node, id_address_node, NOTE_NODE),
-            OscType::Bool(note_on),
+            format!("{}{}{}{}/key", NS_NODE, base_address_node, id_address_node, NOTE_NODE),
+            OscType::Int(key.into()),
             t
         );
+    }
 
-        if note_on {
-            self.osc_sender.push(
-                format!("{}{}{}{}/key", NS_NODE, base_address_node, id_address_node, NOTE_NODE),
-                OscType::Int(key.into()),
-                t
-            );
-            self.osc_sender.push(
-                format!("{}{}{}{}/vel", NS_NODE, base_address_node, id_address_node, NOTE_NODE),
-                OscType::Float(velocity),
-                t
-            );
-            self.osc_sender.push(
-                format!("{}{}{}{}/phase", NS_NODE, base_address_node, id_address_node, NOTE_NODE),
-                OscType::Float(self.phase),
-                t
-            );
-        }
+    pub fn send_glide(&mut self, channel: u8, key: u8, glide: f32, t: (u32, u32)) {
+        let entry = self.entries.get(self.entry_index);
+
+        let id_address_node = AddressNode::id(entry, &self.midi_pitch.zones, self.voices.as_ref(), (channel, key));
+        if !id_address_node.should_send() { return }
+
+        let base_address_node = AddressNode::base(entry, self.entry_index);
+
+        self.osc_sender.push(
+            format!("{}{}{}{}/glide", NS_NODE, base_address_node, id_address_node, NOTE_NODE),
+            OscType::Float(glide),
+            t
+        );
     }
 
     pub fn send_channel(&mut self, ChannelMessage { channel_type, channel, key, value }: ChannelMessage, t: (u32, u32)) {
@@ -162,9 +270,19 @@ impl OscifyPlugin {
 
         let base_address_node = AddressNode::base(entry, self.entry_index);
 
-        let id_address_node = AddressNode::id(entry, (channel, key));
+        let id_address_node = AddressNode::id(entry, &self.midi_pitch.zones, self.voices.as_ref(), (channel, key));
         if !id_address_node.should_send() { return }
 
+        let value = match entry {
+            Some(entry) => match channel_type {
+                ChannelType::Pitch => entry.scale.pitch.apply(value),
+                ChannelType::Pressure => scaled(entry.scale.pressure.as_ref(), value),
+                ChannelType::Timbre => scaled(entry.scale.timbre.as_ref(), value),
+                ChannelType::Pan => scaled(entry.scale.pan.as_ref(), value)
+            },
+            None => value
+        };
+
         self.osc_sender.push(
             format!("{}{}{}{}", NS_NODE, base_address_node, id_address_node, AddressNode::from(channel_type)),
             OscType::Float(value),
@@ -172,6 +290,47 @@ impl OscifyPlugin {
         );
     }
 
+    pub fn send_control(&mut self, ControlMessage { controller, channel, key, value }: ControlMessage, t: (u32, u32)) {
+        let entry = self.entries.get(self.entry_index);
+
+        let control = match entry.and_then(|e| e.control(controller)) {
+            Some(control) => control,
+            None => return
+        };
+
+        let base_address_node = AddressNode::base(entry, self.entry_index);
+
+        let id_address_node = AddressNode::id(entry, &self.midi_pitch.zones, self.voices.as_ref(), (channel, key));
+        if !id_address_node.should_send() { return }
+
+        let arg = if control.toggle() {
+            OscType::Bool(value >= 0.5)
+        } else {
+            OscType::Float(control.apply(value))
+        };
+
+        self.osc_sender.push(
+            format!("{}{}{}{}", NS_NODE, base_address_node, id_address_node, AddressNode::from(control.name())),
+            arg,
+            t
+        );
+    }
+
+    pub fn send_pedal(&mut self, PedalMessage { pedal, channel, key, down }: PedalMessage, t: (u32, u32)) {
+        let entry = self.entries.get(self.entry_index);
+
+        let base_address_node = AddressNode::base(entry, self.entry_index);
+
+        let id_address_node = AddressNode::id(entry, &self.midi_pitch.zones, self.voices.as_ref(), (channel, key));
+        if !id_address_node.should_send() { return }
+
+        self.osc_sender.push(
+            format!("{}{}{}{}", NS_NODE, base_address_node, id_address_node, AddressNode::from(pedal)),
+            OscType::Bool(down),
+            t
+        );
+    }
+
     pub fn send_param(&mut self, ParamMessage { param_index, value } : ParamMessage, t: (u32, u32)) {
         let entry = self.entries.get(self.entry_index);
 
@@ -179,6 +338,8 @@ impl OscifyPlugin {
 
         let param_address_node = AddressNode::param(entry, param_index);
 
+        let value = entry.map_or(value, |e| e.param_value(param_index, value));
+
         self.osc_sender.push(
             format!("{}{}{}{}", NS_NODE, base_address_node, PARAM_NODE, param_address_node),
             OscType::Float(value),
@@ -186,6 +347,105 @@ impl OscifyPlugin {
         );
     }
 
+    pub fn send_panic(&mut self, channel: u8, t: (u32, u32)) {
+        let entry = self.entries.get(self.entry_index);
+
+        let base_address_node = AddressNode::base(entry, self.entry_index);
+
+        self.osc_sender.push(
+            format!("{}{}{}", NS_NODE, base_address_node, PANIC_NODE),
+            OscType::Int(channel.into()),
+            t
+        );
+    }
+
+    pub fn send_program(&mut self, program: u8, t: (u32, u32)) {
+        let entry = self.entries.get(self.entry_index);
+
+        let base_address_node = AddressNode::base(entry, self.entry_index);
+
+        self.osc_sender.push(
+            format!("{}{}{}", NS_NODE, base_address_node, PROGRAM_NODE),
+            OscType::Int(program.into()),
+            t
+        );
+    }
+
+    pub fn send_sysex(&mut self, data: &[u8], t: (u32, u32)) {
+        let entry = self.entries.get(self.entry_index);
+
+        let sysex_address_node = match entry.and_then(|e| e.sysex.as_ref()) {
+            Some(sysex) => AddressNode::from(&sysex.address[..]),
+            None => return
+        };
+
+        let base_address_node = AddressNode::base(entry, self.entry_index);
+
+        self.osc_sender.push(
+            format!("{}{}{}", NS_NODE, base_address_node, sysex_address_node),
+            OscType::Blob(data.to_vec()),
+            t
+        );
+    }
+
+    pub fn send_transport(&mut self, event: TransportEvent, t: (u32, u32)) {
+        let entry = self.entries.get(self.entry_index);
+
+        let base_address_node = AddressNode::base(entry, self.entry_index);
+
+        let (name, arg) = match event {
+            TransportEvent::Playing(playing) => ("playing", OscType::Bool(playing)),
+            TransportEvent::Tempo(bpm) => ("tempo", OscType::Float(bpm)),
+            TransportEvent::Beat(beat) => ("beat", OscType::Float(beat))
+        };
+
+        self.osc_sender.push(
+            format!("{}{}{}/{}", NS_NODE, base_address_node, TRANSPORT_NODE, name),
+            arg,
+            t
+        );
+    }
+
+    pub fn send_levels(&mut self, channel: usize, levels: Levels, t: (u32, u32)) {
+        let entry = self.entries.get(self.entry_index);
+
+        let base_address_node = AddressNode::base(entry, self.entry_index);
+
+        let channel_address_node = AddressNode::from(channel);
+
+        for &(name, value) in &[("rms", levels.rms), ("peak", levels.peak), ("env", levels.envelope)] {
+            self.osc_sender.push(
+                format!("{}{}{}{}/{}", NS_NODE, base_address_node, AUDIO_NODE, channel_address_node, name),
+                OscType::Float(value),
+                t
+            );
+        }
+    }
+
+    pub fn send_spectrum(&mut self, bands: &[f32], format: SpectrumFormat, t: (u32, u32)) {
+        let entry = self.entries.get(self.entry_index);
+
+        let base_address_node = AddressNode::base(entry, self.entry_index);
+
+        let args = match format {
+            SpectrumFormat::Array => bands.iter().map(|&band| OscType::Float(band)).collect(),
+            SpectrumFormat::Blob => {
+                let mut bytes = Vec::with_capacity(bands.len() * 4);
+                for band in bands {
+                    let bits = band.to_bits();
+                    bytes.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+                }
+                vec![OscType::Blob(bytes)]
+            }
+        };
+
+        self.osc_sender.push_args(
+            format!("{}{}{}", NS_NODE, base_address_node, SPECTRUM_NODE),
+            args,
+            t
+        );
+    }
+
     pub fn flush_midi_events(&mut self) {
         let result = self.osc_sender.flush();
         if result.is_err() {
@@ -0,0 +1,143 @@
+// how user params are smoothed and rate limited before they're sent
+
+const SETTLED: f32 = 1e-4;
+// times per block that smoothed params are advanced and may be sent
+const POINTS_PER_BLOCK: usize = 4;
+
+#[derive(Copy, Clone, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Smoothing {
+    None,
+    Linear(f32), // seconds for a full range sweep
+    Exp(f32) // time constant in seconds
+}
+
+impl Default for Smoothing {
+    fn default() -> Self { Smoothing::None }
+}
+
+#[derive(Copy, Clone, Default, Debug)]
+pub struct RateLimit {
+    pub interval: f32, // minimum seconds between sends
+    pub threshold: f32, // changes smaller than this aren't sent
+    pub smoothing: Smoothing
+}
+
+#[derive(Copy, Clone, Debug)]
+struct SmoothedParam {
+    current: f32,
+    target: f32, // as of the previous block
+    sent: f32,
+    sent_at: f64 // seconds
+}
+
+#[derive(Debug)]
+pub struct ParamSmoother {
+    params: Vec<SmoothedParam>
+}
+
+impl ParamSmoother {
+    pub fn new(values: &[f32]) -> Self {
+        ParamSmoother {
+            params: values.iter().map(|&value| SmoothedParam {
+                current: value,
+                target: value,
+                sent: value,
+                sent_at: 0.
+            }).collect()
+        }
+    }
+
+    // advances every param by one block, returns (index, value, seconds into the block) to send,
+    // smoothed values are interpolated at POINTS_PER_BLOCK evenly spaced times
+    pub fn process<F>(&mut self, targets: &[f32], limits: F, block: f32, now: f64) -> Vec<(usize, f32, f32)>
+        where F: Fn(usize) -> RateLimit
+    {
+        let step = block / POINTS_PER_BLOCK as f32;
+        let mut changes = vec![];
+        for (index, (param, &target)) in self.params.iter_mut().zip(targets).enumerate() {
+            let limit = limits(index);
+            // whether the target moved is judged per block, not per point
+            let steady = param.target == target;
+            param.target = target;
+
+            for point in 0..POINTS_PER_BLOCK {
+                let diff = target - param.current;
+                param.current = match limit.smoothing {
+                    Smoothing::Linear(time) if time > 0. => {
+                        let max = step / time;
+                        param.current + diff.max(-max).min(max)
+                    },
+                    Smoothing::Exp(time) if time > 0. && diff.abs() > SETTLED =>
+                        param.current + diff * (1. - (-step / time).exp()),
+                    _ => target
+                };
+
+                // the value a param comes to rest at is sent even when it's within the threshold
+                let settled = steady && param.current == target;
+
+                let moved = (param.current - param.sent).abs();
+                if moved == 0. || (moved < limit.threshold && !settled) { continue }
+                let offset = step * point as f32;
+                let at = now + offset as f64;
+                if at - param.sent_at < limit.interval as f64 { continue }
+
+                param.sent = param.current;
+                param.sent_at = at;
+                changes.push((index, param.current, offset));
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn threshold(_: usize) -> RateLimit {
+        RateLimit { interval: 0., threshold: 0.1, smoothing: Smoothing::None }
+    }
+
+    #[test]
+    fn small_moves_wait_until_settled() {
+        let mut smoother = ParamSmoother::new(&[0.]);
+        assert!(smoother.process(&[0.02], threshold, 0.01, 1.).is_empty());
+        assert!(smoother.process(&[0.04], threshold, 0.01, 2.).is_empty());
+        assert_eq!(smoother.process(&[0.04], threshold, 0.01, 3.), vec![(0, 0.04, 0.)]);
+        assert!(smoother.process(&[0.04], threshold, 0.01, 4.).is_empty());
+    }
+
+    #[test]
+    fn linear_smoothing_ends_on_target() {
+        let limit = |_| RateLimit { interval: 0., threshold: 0.3, smoothing: Smoothing::Linear(1.) };
+        let mut smoother = ParamSmoother::new(&[0.]);
+        let sent: Vec<_> = (0..10)
+            .flat_map(|block| smoother.process(&[0.5], limit, 0.1, block as f64))
+            .collect();
+        assert_eq!(sent.last().map(|&(_, value, _)| value), Some(0.5));
+    }
+
+    #[test]
+    fn smoothing_is_interpolated_within_the_block() {
+        let limit = |_| RateLimit { interval: 0., threshold: 0., smoothing: Smoothing::Linear(1.) };
+        let mut smoother = ParamSmoother::new(&[0.]);
+        let sent = smoother.process(&[1.], limit, 0.4, 0.);
+        assert_eq!(sent.len(), POINTS_PER_BLOCK);
+        for (point, &(_, value, offset)) in sent.iter().enumerate() {
+            assert!((offset - 0.1 * point as f32).abs() < 1e-6);
+            assert!((value - 0.1 * (point + 1) as f32).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn interval_applies_between_points() {
+        let limit = |_| RateLimit { interval: 0.15, threshold: 0., smoothing: Smoothing::Linear(1.) };
+        let mut smoother = ParamSmoother::new(&[0.]);
+        let offsets: Vec<_> = smoother.process(&[1.], limit, 0.4, 1.).into_iter()
+            .map(|(_, _, offset)| offset)
+            .collect();
+        assert_eq!(offsets.len(), 2);
+        assert!((offsets[1] - offsets[0] - 0.2).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,190 @@
+use std::f32::consts::PI;
+
+use fft::Fft;
+
+fn default_size() -> usize { 1024 }
+fn default_overlap() -> f32 { 0.5 }
+fn default_bands() -> usize { 16 }
+fn default_min_freq() -> f32 { 20. }
+fn default_max_freq() -> f32 { 20000. }
+fn default_interval() -> f32 { 0.05 }
+
+#[derive(Copy, Clone, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Window { Rect, Hann, Hamming, Blackman }
+
+impl Default for Window {
+    fn default() -> Self { Window::Hann }
+}
+
+impl Window {
+    fn apply(&self, i: usize, size: usize) -> f32 {
+        let x = 2. * PI * i as f32 / size as f32;
+        match *self {
+            Window::Rect => 1.,
+            Window::Hann => 0.5 - 0.5 * x.cos(),
+            Window::Hamming => 0.54 - 0.46 * x.cos(),
+            Window::Blackman => 0.42 - 0.5 * x.cos() + 0.08 * (2. * x).cos()
+        }
+    }
+}
+
+// how the bands are sent
+#[derive(Copy, Clone, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SpectrumFormat {
+    Array, // one float argument per band
+    Blob // little endian f32s
+}
+
+impl Default for SpectrumFormat {
+    fn default() -> Self { SpectrumFormat::Array }
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct SpectrumConfig {
+    #[serde(default = "default_size")]
+    pub size: usize, // rounded up to a power of two
+    #[serde(default = "default_overlap")]
+    pub overlap: f32, // 0..1
+    #[serde(default)]
+    pub window: Window,
+    #[serde(default = "default_bands")]
+    pub bands: usize,
+    #[serde(default = "default_min_freq")]
+    pub min_freq: f32,
+    #[serde(default = "default_max_freq")]
+    pub max_freq: f32,
+    #[serde(default = "default_interval")]
+    pub interval: f32, // seconds between sends
+    #[serde(default)]
+    pub format: SpectrumFormat
+}
+
+// all buffers are allocated in new, process doesn't allocate
+#[derive(Debug)]
+pub struct SpectrumAnalyzer {
+    config: SpectrumConfig,
+    fft: Fft,
+    window: Vec<f32>,
+    window_gain: f32,
+    history: Vec<f32>, // ring buffer of the last size samples
+    position: usize,
+    hop: usize,
+    since_frame: usize,
+    re: Vec<f32>,
+    im: Vec<f32>,
+    sample_rate: f32,
+    edges: Vec<(usize, usize)>, // bin range of each band
+    bands: Vec<f32>,
+    fresh: bool, // a frame was analyzed since the last send
+    elapsed: f32
+}
+
+impl SpectrumAnalyzer {
+    pub fn new(config: &SpectrumConfig) -> Self {
+        let size = config.size.max(2).next_power_of_two();
+        let window: Vec<f32> = (0..size).map(|i| config.window.apply(i, size)).collect();
+        let window_gain = window.iter().sum::<f32>() / 2.;
+        let overlap = config.overlap.max(0.).min(0.99);
+        SpectrumAnalyzer {
+            config: config.clone(),
+            fft: Fft::new(size),
+            window,
+            window_gain,
+            history: vec![0.; size],
+            position: 0,
+            hop: ((size as f32 * (1. - overlap)) as usize).max(1),
+            since_frame: 0,
+            re: vec![0.; size],
+            im: vec![0.; size],
+            sample_rate: 0.,
+            edges: vec![(0, 0); config.bands],
+            bands: vec![0.; config.bands],
+            fresh: false,
+            elapsed: 0.
+        }
+    }
+
+    // forgets earlier audio, keeping the buffers
+    pub fn reset(&mut self) {
+        for sample in self.history.iter_mut() {
+            *sample = 0.;
+        }
+        self.since_frame = 0;
+        self.fresh = false;
+        self.elapsed = 0.;
+    }
+
+    pub fn format(&self) -> SpectrumFormat {
+        self.config.format
+    }
+
+    // log spaced from min_freq to max_freq, at least one bin each
+    fn update_edges(&mut self, sample_rate: f32) {
+        let size = self.fft.size();
+        let nyquist_bin = size / 2;
+        let bin_width = sample_rate / size as f32;
+        let (min, max) = (self.config.min_freq.max(bin_width), self.config.max_freq.min(sample_rate / 2.));
+        let count = self.edges.len();
+        let bin = |band: usize| {
+            let freq = min * (max / min).powf(band as f32 / count as f32);
+            ((freq / bin_width) as usize).min(nyquist_bin)
+        };
+        for (band, edge) in self.edges.iter_mut().enumerate() {
+            let low = bin(band).min(nyquist_bin - 1);
+            *edge = (low, bin(band + 1).max(low + 1));
+        }
+        self.sample_rate = sample_rate;
+    }
+
+    // channels are mixed to mono
+    pub fn process(&mut self, inputs: &[&[f32]], sample_rate: f32) {
+        if inputs.is_empty() || sample_rate <= 0. { return }
+        if sample_rate != self.sample_rate {
+            self.update_edges(sample_rate);
+        }
+
+        let gain = 1. / inputs.len() as f32;
+        let samples = inputs.iter().map(|input| input.len()).min().unwrap_or(0);
+        for i in 0..samples {
+            let sample = inputs.iter().map(|input| input[i]).sum::<f32>() * gain;
+            self.history[self.position] = sample;
+            self.position = (self.position + 1) % self.history.len();
+            self.since_frame += 1;
+            if self.since_frame >= self.hop {
+                self.since_frame = 0;
+                self.analyze();
+            }
+        }
+    }
+
+    fn analyze(&mut self) {
+        let size = self.history.len();
+        for i in 0..size {
+            // oldest sample first
+            self.re[i] = self.history[(self.position + i) % size] * self.window[i];
+            self.im[i] = 0.;
+        }
+        self.fft.process(&mut self.re, &mut self.im);
+
+        let (re, im) = (&self.re, &self.im);
+        for (band, &(low, high)) in self.bands.iter_mut().zip(&self.edges) {
+            let peak = (low..high)
+                .map(|bin| (re[bin] * re[bin] + im[bin] * im[bin]).sqrt())
+                .fold(0., f32::max);
+            // a full scale sine in a band is about 1
+            *band = peak / self.window_gain;
+        }
+        self.fresh = true;
+    }
+
+    // the latest bands, once per interval
+    pub fn take(&mut self, block: f32) -> Option<&[f32]> {
+        self.elapsed += block;
+        if self.elapsed < self.config.interval || !self.fresh { return None }
+        self.elapsed = 0.;
+        self.fresh = false;
+        Some(&self.bands)
+    }
+}
@@ -0,0 +1,133 @@
+use std::error::Error;
+use serde_json;
+
+use config;
+use vst::ProgramMode;
+
+// bump when the format changes, and migrate older versions in decode
+const STATE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ParamState {
+    pub name: Option<String>,
+    pub value: f32
+}
+
+// everything that isn't in the config, saved in the host project
+#[derive(Serialize, Deserialize, Debug)]
+pub struct State {
+    pub version: u32,
+    pub entry: Option<String>, // by name, so reordering the config keeps the selection
+    #[serde(default)]
+    pub entry_index: usize, // fallback for renamed entries
+    #[serde(default)]
+    pub phase: f32,
+    #[serde(default)]
+    pub program_mode: ProgramMode,
+    #[serde(default)]
+    pub params: Vec<ParamState>,
+    pub instance_id: Option<u16>,
+    pub destination: Option<String> // overrides the default OSC destination
+}
+
+impl State {
+    pub fn new() -> Self {
+        State {
+            version: STATE_VERSION,
+            entry: None,
+            entry_index: 0,
+            phase: 0.,
+            program_mode: ProgramMode::default(),
+            params: vec![],
+            instance_id: None,
+            destination: None
+        }
+    }
+
+    pub fn entry_index(&self, entries: &[config::Entry]) -> usize {
+        let by_name = self.entry.as_ref()
+            .and_then(|name| entries.iter().position(|entry| &entry.name == name));
+        match by_name {
+            Some(index) => index,
+            None if self.entry_index < entries.len() => {
+                info!("Entry {:?} not found, using index {}", self.entry, self.entry_index);
+                self.entry_index
+            },
+            None => 0
+        }
+    }
+
+    // saved params matched by name, or by position when the name is gone
+    // and no other param took that slot, unused slots aren't restored
+    pub fn param_indices(&self, entry: Option<&config::Entry>) -> Vec<(usize, f32)> {
+        let named = self.params.iter().enumerate()
+            .filter_map(|(i, param)| param.name.as_ref().map(|name| (i, name, param.value)));
+        let (matched, missing): (Vec<_>, Vec<_>) = named
+            .map(|(i, name, value)| {
+                let index = entry.and_then(|entry| entry.params.iter().position(|p| p.name() == name));
+                (i, index, value)
+            })
+            .partition(|&(_, index, _)| index.is_some());
+
+        let mut indices: Vec<_> = matched.into_iter()
+            .filter_map(|(_, index, value)| index.map(|index| (index, value)))
+            .collect();
+        for (i, _, value) in missing {
+            if indices.iter().all(|&(index, _)| index != i) {
+                indices.push((i, value));
+            }
+        }
+        indices
+    }
+}
+
+pub fn encode(state: &State) -> Vec<u8> {
+    serde_json::to_vec(state).unwrap_or_else(|err| {
+        error!("Couldn't save state: {}", err);
+        vec![]
+    })
+}
+
+pub fn decode(data: &[u8]) -> Result<State, Box<Error>> {
+    let state: State = serde_json::from_slice(data)?;
+    if state.version > STATE_VERSION {
+        info!("State version {} is newer than {}, loading known fields", state.version, STATE_VERSION);
+    }
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(params: &str) -> config::Entry {
+        let json = format!(r#"[{{ "type": "Param", "name": "Test", "address": "test", "params": {} }}]"#, params);
+        config::parse(&json).unwrap().remove(0)
+    }
+
+    fn state(params: Vec<(Option<&str>, f32)>) -> State {
+        let mut state = State::new();
+        state.params = params.into_iter()
+            .map(|(name, value)| ParamState { name: name.map(|name| name.to_string()), value })
+            .collect();
+        state
+    }
+
+    #[test]
+    fn reordered_params_keep_values() {
+        let state = state(vec![(Some("a"), 0.7), (None, 0.), (None, 0.)]);
+        assert_eq!(state.param_indices(Some(&entry(r#"["b", "a"]"#))), vec![(1, 0.7)]);
+    }
+
+    #[test]
+    fn renamed_params_fall_back_to_position() {
+        let state = state(vec![(Some("old"), 0.3), (Some("a"), 0.7)]);
+        assert_eq!(state.param_indices(Some(&entry(r#"["new", "a"]"#))), vec![(1, 0.7), (0, 0.3)]);
+    }
+
+    #[test]
+    fn name_matches_take_precedence() {
+        let state = state(vec![(Some("old"), 0.3), (Some("a"), 0.7)]);
+        assert_eq!(state.param_indices(Some(&entry(r#"["a", "c"]"#))), vec![(0, 0.7)]);
+    }
+}
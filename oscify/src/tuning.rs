@@ -0,0 +1,337 @@
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+use serde::{Deserialize, Deserializer};
+
+const KEYS: usize = 128;
+const A4_KEY: f64 = 69.;
+const A4_HZ: f64 = 440.;
+
+// scala files for an entry, relative to the config directory
+#[derive(Deserialize, Debug)]
+struct TuningFiles {
+    scl: String,
+    kbm: Option<String>
+}
+
+#[derive(Debug)]
+pub struct ScalaScale {
+    pub description: String,
+    pub cents: Vec<f64> // degrees 1..n, the last one is the period
+}
+
+#[derive(Debug)]
+pub struct KeyboardMapping {
+    pub first_key: u8,
+    pub last_key: u8,
+    pub middle_key: u8, // key of scale degree 0
+    pub reference_key: u8,
+    pub reference_freq: f64,
+    pub octave_degree: usize,
+    pub map: Vec<Option<usize>> // empty for a linear mapping
+}
+
+impl Default for KeyboardMapping {
+    // 12-TET middle C on key 60, so that a 12 note equal scale is unchanged
+    fn default() -> Self {
+        KeyboardMapping {
+            first_key: 0,
+            last_key: (KEYS - 1) as u8,
+            middle_key: 60,
+            reference_key: 60,
+            reference_freq: A4_HZ * ((60. - A4_KEY) / 12.).exp2(),
+            octave_degree: 0,
+            map: Vec::new()
+        }
+    }
+}
+
+// fractional midi note for every key, None for unmapped keys
+#[derive(Clone, Debug)]
+pub struct Tuning {
+    notes: Vec<Option<f32>>
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning { notes: (0..KEYS).map(|key| Some(key as f32)).collect() }
+    }
+}
+
+impl Tuning {
+    pub fn new(scale: &ScalaScale, mapping: &KeyboardMapping) -> Result<Self, Box<Error>> {
+        let reference = scale_cents(scale, mapping, mapping.reference_key)
+            .ok_or("reference key is not mapped")?;
+        let reference_note = A4_KEY + 12. * (mapping.reference_freq / A4_HZ).log2();
+
+        let notes = (0..KEYS).map(|key| {
+            scale_cents(scale, mapping, key as u8)
+                .map(|cents| (reference_note + (cents - reference) / 100.) as f32)
+        }).collect();
+
+        Ok(Tuning { notes })
+    }
+
+    // keys past 127 from malformed midi are unmapped
+    pub fn get_note(&self, key: u8) -> Option<f32> {
+        self.notes.get(key as usize).cloned().and_then(|note| note)
+    }
+
+    // keys past 127 from malformed sysex are ignored
+    pub fn set_note(&mut self, key: u8, note: f32) {
+        if let Some(slot) = self.notes.get_mut(key as usize) {
+            *slot = Some(note);
+        }
+    }
+}
+
+// cents of a key relative to the middle key
+fn scale_cents(scale: &ScalaScale, mapping: &KeyboardMapping, key: u8) -> Option<f64> {
+    if key < mapping.first_key || key > mapping.last_key { return None }
+
+    let steps = (key as i32) - (mapping.middle_key as i32);
+    let degree = if mapping.map.is_empty() {
+        steps
+    } else {
+        let size = mapping.map.len() as i32;
+        let octave_degree = match mapping.octave_degree {
+            0 => scale.cents.len(),
+            degree => degree
+        } as i32;
+        let (octave, index) = (div_floor(steps, size), mod_floor(steps, size));
+        match mapping.map[index as usize] {
+            Some(degree) => degree as i32 + octave * octave_degree,
+            None => return None
+        }
+    };
+
+    let size = scale.cents.len() as i32;
+    let period = scale.cents[scale.cents.len() - 1];
+    let (octave, index) = (div_floor(degree, size), mod_floor(degree, size));
+    let cents = if index == 0 { 0. } else { scale.cents[index as usize - 1] };
+    Some(octave as f64 * period + cents)
+}
+
+fn div_floor(a: i32, b: i32) -> i32 {
+    (a as f64 / b as f64).floor() as i32
+}
+
+fn mod_floor(a: i32, b: i32) -> i32 {
+    ((a % b) + b) % b
+}
+
+// skips comments, whitespace is significant only in the description
+fn lines(s: &str) -> Vec<&str> {
+    s.lines().filter(|line| !line.starts_with('!')).collect()
+}
+
+fn first_token(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+fn parse_pitch(line: &str) -> Result<f64, Box<Error>> {
+    let token = first_token(line);
+    if token.contains('.') {
+        return Ok(token.parse::<f64>()?)
+    }
+    let mut parts = token.splitn(2, '/');
+    let numerator = parts.next().unwrap_or("").parse::<u64>()? as f64;
+    let denominator = match parts.next() {
+        Some(denominator) => denominator.parse::<u64>()? as f64,
+        None => 1.
+    };
+    if numerator <= 0. || denominator <= 0. {
+        return Err(format!("invalid ratio: {}", token).into())
+    }
+    Ok(1200. * (numerator / denominator).log2())
+}
+
+pub fn parse_scl(s: &str) -> Result<ScalaScale, Box<Error>> {
+    let lines = lines(s);
+    let description = lines.get(0).ok_or("missing description")?.trim().to_string();
+    let count = first_token(lines.get(1).ok_or("missing note count")?).parse::<usize>()?;
+    if count == 0 {
+        return Err("scale has no notes".into())
+    }
+    let pitches = lines.iter().skip(2).filter(|line| !line.trim().is_empty());
+    let cents = pitches.take(count).map(|line| parse_pitch(line)).collect::<Result<Vec<_>, _>>()?;
+    if cents.len() < count {
+        return Err(format!("expected {} notes, found {}", count, cents.len()).into())
+    }
+    Ok(ScalaScale { description, cents })
+}
+
+pub fn parse_kbm(s: &str) -> Result<KeyboardMapping, Box<Error>> {
+    let tokens: Vec<_> = lines(s).into_iter()
+        .map(first_token)
+        .filter(|token| !token.is_empty())
+        .collect();
+    if tokens.len() < 7 {
+        return Err("incomplete keyboard mapping header".into())
+    }
+    let size = tokens[0].parse::<usize>()?;
+    // missing entries at the end are unmapped
+    let map = (0..size).map(|i| match tokens.get(7 + i) {
+        None | Some(&"x") => Ok(None),
+        Some(token) => token.parse::<usize>().map(Some)
+    }).collect::<Result<Vec<_>, _>>()?;
+
+    Ok(KeyboardMapping {
+        first_key: tokens[1].parse()?,
+        last_key: tokens[2].parse()?,
+        middle_key: tokens[3].parse()?,
+        reference_key: tokens[4].parse()?,
+        reference_freq: tokens[5].parse()?,
+        octave_degree: tokens[6].parse()?,
+        map
+    })
+}
+
+fn read(path: &str) -> Result<String, Box<Error>> {
+    let mut full_path = env::current_dir()?;
+    full_path.push(Path::new(path));
+
+    let f = File::open(&full_path)?;
+    let mut file = BufReader::new(&f);
+
+    let mut s = String::new();
+    file.read_to_string(&mut s)?;
+    Ok(s)
+}
+
+fn load(files: &TuningFiles) -> Result<Tuning, Box<Error>> {
+    let scale = parse_scl(&read(&files.scl)?)?;
+    let mapping = match files.kbm {
+        Some(ref kbm) => parse_kbm(&read(kbm)?)?,
+        None => KeyboardMapping::default()
+    };
+    info!("Loaded tuning: {}", scale.description);
+    Tuning::new(&scale, &mapping)
+}
+
+// reads the scala files while the config is loaded,
+// an entry whose tuning can't be loaded falls back to 12-TET
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Tuning>, D::Error>
+    where D: Deserializer<'de>
+{
+    match Option::<TuningFiles>::deserialize(deserializer)? {
+        Some(files) => Ok(load(&files).map_err(|err| {
+            error!("Couldn't load tuning {}: {}", files.scl, err);
+        }).ok()),
+        None => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config;
+
+    const EDO_12: &str = "! 12edo.scl
+!
+12 tone equal temperament
+12
+!
+100.0
+200.
+300.0
+400.0
+500.0
+600.0
+700.0
+800.0
+900.0
+1000.0
+1100.0
+2/1
+";
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn scl_cents_and_ratios() {
+        let scale = parse_scl("! just.scl\nJust fifth\n 3\n! degrees\n3/2\n\n701.955 fifth\n 2 octave\n").unwrap();
+        assert_eq!(scale.description, "Just fifth");
+        assert_eq!(scale.cents.len(), 3);
+        assert_close(scale.cents[0], 1200. * 1.5f64.log2());
+        assert_close(scale.cents[1], 701.955);
+        assert_close(scale.cents[2], 1200.);
+    }
+
+    #[test]
+    fn scl_skips_comments() {
+        let scale = parse_scl(EDO_12).unwrap();
+        assert_eq!(scale.description, "12 tone equal temperament");
+        assert_eq!(scale.cents.len(), 12);
+        assert_close(scale.cents[1], 200.);
+    }
+
+    #[test]
+    fn scl_blank_description() {
+        let scale = parse_scl("!\n\n1\n2/1\n").unwrap();
+        assert_eq!(scale.description, "");
+        assert_eq!(scale.cents.len(), 1);
+    }
+
+    #[test]
+    fn scl_too_few_notes() {
+        assert!(parse_scl("Short\n3\n100.0\n2/1\n").is_err());
+        assert!(parse_scl("Empty\n0\n").is_err());
+    }
+
+    #[test]
+    fn kbm_unmapped_keys() {
+        let mapping = parse_kbm("! whites.kbm\n12\n0\n127\n60\n69\n440.0\n12\n0\nx\n2\nx\n4\n5\nx\n7\nx\n9\nx\n11\n").unwrap();
+        assert_eq!(mapping.map.len(), 12);
+        assert_eq!(mapping.map[0], Some(0));
+        assert_eq!(mapping.map[1], None);
+        assert_eq!(mapping.map[11], Some(11));
+
+        let tuning = Tuning::new(&parse_scl(EDO_12).unwrap(), &mapping).unwrap();
+        assert_eq!(tuning.get_note(60), Some(60.));
+        assert_eq!(tuning.get_note(61), None);
+        assert_eq!(tuning.get_note(73), None);
+    }
+
+    #[test]
+    fn kbm_linear_mapping() {
+        let mapping = parse_kbm("0\n0\n127\n60\n69\n440.0\n0\n").unwrap();
+        assert!(mapping.map.is_empty());
+        let tuning = Tuning::new(&parse_scl(EDO_12).unwrap(), &mapping).unwrap();
+        assert_eq!(tuning.get_note(69), Some(69.));
+        assert_eq!(tuning.get_note(0), Some(0.));
+    }
+
+    #[test]
+    fn equal_scale_keeps_keys() {
+        let tuning = Tuning::new(&parse_scl(EDO_12).unwrap(), &KeyboardMapping::default()).unwrap();
+        for key in 0..KEYS {
+            let note = tuning.get_note(key as u8).unwrap();
+            assert!((note - key as f32).abs() < 1e-3, "key {} is {}", key, note);
+        }
+    }
+
+    #[test]
+    fn keys_past_127_are_unmapped() {
+        assert_eq!(Tuning::default().get_note(127), Some(127.));
+        assert_eq!(Tuning::default().get_note(200), None);
+    }
+
+    #[test]
+    fn missing_files_fall_back_to_equal() {
+        let entries = config::parse(r#"[{
+            "type": "Poly",
+            "name": "Missing tuning",
+            "address": "missing",
+            "params": [],
+            "tuning": { "scl": "missing.scl" }
+        }]"#).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].tuning.is_none());
+    }
+}
@@ -0,0 +1,94 @@
+#[derive(Copy, Clone, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum StealPolicy { Oldest, Quietest, Lowest, Highest }
+
+impl Default for StealPolicy {
+    fn default() -> Self { StealPolicy::Oldest }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Voice {
+    channel: u8,
+    key: u8,
+    velocity: u8
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Slot {
+    voice: Option<Voice>,
+    since: u64 // when the slot was last assigned or freed
+}
+
+#[derive(Debug)]
+pub struct VoiceAllocator {
+    slots: Vec<Slot>, // index is voice id
+    policy: StealPolicy,
+    clock: u64
+}
+
+impl VoiceAllocator {
+    pub fn new(count: usize, policy: StealPolicy) -> Self {
+        VoiceAllocator {
+            slots: vec![Slot { voice: None, since: 0 }; count.max(1)],
+            policy,
+            clock: 0
+        }
+    }
+
+    pub fn find(&self, channel: u8, key: u8) -> Option<usize> {
+        self.slots.iter().position(|slot| match slot.voice {
+            Some(voice) => voice.channel == channel && voice.key == key,
+            None => false
+        })
+    }
+
+    // keys sounding on a channel
+    pub fn keys(&self, channel: u8) -> Vec<u8> {
+        self.slots.iter()
+            .filter_map(|slot| slot.voice)
+            .filter(|voice| voice.channel == channel)
+            .map(|voice| voice.key)
+            .collect()
+    }
+
+    // picks a voice for a new note, and the note it steals if there was no free voice
+    pub fn allocate(&self, channel: u8, key: u8) -> (usize, Option<(u8, u8)>) {
+        if let Some(id) = self.find(channel, key) {
+            return (id, None)
+        }
+        let free = self.slots.iter().enumerate()
+            .filter(|&(_, slot)| slot.voice.is_none())
+            .min_by_key(|&(_, slot)| slot.since)
+            .map(|(id, _)| id);
+        if let Some(id) = free {
+            return (id, None)
+        }
+        let policy = self.policy;
+        let (id, stolen) = self.slots.iter().enumerate()
+            .filter_map(|(id, slot)| slot.voice.map(|voice| (id, voice, slot.since)))
+            .min_by_key(|&(_, voice, since)| match policy {
+                StealPolicy::Oldest => (0, since),
+                StealPolicy::Quietest => (voice.velocity as i32, since),
+                StealPolicy::Lowest => (voice.key as i32, since),
+                StealPolicy::Highest => (-(voice.key as i32), since)
+            })
+            .map(|(id, voice, _)| (id, voice))
+            .unwrap();
+        (id, Some((stolen.channel, stolen.key)))
+    }
+
+    pub fn assign(&mut self, id: usize, channel: u8, key: u8, velocity: u8) {
+        self.clock += 1;
+        self.slots[id] = Slot {
+            voice: Some(Voice { channel, key, velocity }),
+            since: self.clock
+        };
+    }
+
+    pub fn release(&mut self, channel: u8, key: u8) {
+        if let Some(id) = self.find(channel, key) {
+            self.clock += 1;
+            self.slots[id] = Slot { voice: None, since: self.clock };
+        }
+    }
+}
@@ -1,13 +1,29 @@
 use time;
 use vst2::event::{Event};
+use vst2::buffer::AudioBuffer;
 use vst2::{plugin, api};
+use vst2::plugin::HostCallback;
+use vst2::host::Host;
 
 use logger;
 use config;
 use osc;
+use midi;
 use midi::Message;
 use sender;
 use midi_pitch::MidiPitch;
+use mpe::Zone;
+use key_pressure::KeyPressure;
+use controllers::{Controllers, ControllerEvent, Param, u14_into_f32};
+use voices::VoiceAllocator;
+use mono::{MonoConfig, Note, NoteStack};
+use pedals::{Pedal, Pedals};
+use clock::MidiClock;
+use state;
+use state::{State, ParamState};
+use smoothing::{ParamSmoother, RateLimit};
+use analysis::{AudioAnalyzer, CHANNELS};
+use spectrum::SpectrumAnalyzer;
 
 fn u7_into_f32(x: u8) -> f32 {
     x as f32 / (0x80 as f32) // should be 0x7F but 0x80 centers things and pressure goes that high
@@ -15,13 +31,59 @@ fn u7_into_f32(x: u8) -> f32 {
 
 const EPOCH_DELTA: i64 = 2208988800i64;
 const NTP_SCALE: f64 = 4294967295.0_f64;
-// offset should be in nanoseconds, less than a second
+// offset is in nanoseconds, whole seconds carry into sec
 fn get_time(t: time::Timespec, offset: f64) -> (u32, u32) {
-    let sec = t.sec + EPOCH_DELTA;
-    let frac = (t.nsec as f64 + offset) * NTP_SCALE / 1e10;
+    let ns = t.nsec as f64 + offset;
+    let sec = t.sec + EPOCH_DELTA + (ns / 1e9) as i64;
+    let frac = (ns % 1e9) * NTP_SCALE / 1e9;
     (sec as u32, frac as u32)
 }
 
+fn into_seconds((sec, frac): (u32, u32)) -> f64 {
+    sec as f64 + frac as f64 / NTP_SCALE
+}
+
+// what program changes do
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ProgramMode { Ignore, Forward, Switch, ForwardAndSwitch }
+
+impl Default for ProgramMode {
+    fn default() -> Self { ProgramMode::Ignore }
+}
+
+const PROGRAM_MODES: [ProgramMode; 4] = [
+    ProgramMode::Ignore,
+    ProgramMode::Forward,
+    ProgramMode::Switch,
+    ProgramMode::ForwardAndSwitch
+];
+
+impl ProgramMode {
+    fn forwards(&self) -> bool {
+        match *self {
+            ProgramMode::Forward | ProgramMode::ForwardAndSwitch => true,
+            _ => false
+        }
+    }
+
+    // program number selects the entry
+    fn switches(&self) -> bool {
+        match *self {
+            ProgramMode::Switch | ProgramMode::ForwardAndSwitch => true,
+            _ => false
+        }
+    }
+
+    fn name(&self) -> &str {
+        match *self {
+            ProgramMode::Ignore => "Ignore",
+            ProgramMode::Forward => "Forward",
+            ProgramMode::Switch => "Switch entry",
+            ProgramMode::ForwardAndSwitch => "Forward, switch entry"
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct OscifyPlugin {
     sample_rate: f32,
@@ -30,88 +92,511 @@ pub struct OscifyPlugin {
     pub entries: Vec<config::Entry>,
     pub entry_index: usize,
     pub phase: f32,
-    pub params: [f32; 8],
-    pub midi_pitch: MidiPitch
+    pub program_mode: ProgramMode,
+    pub params: Vec<f32>, // sized to the entry with the most params
+    pub midi_pitch: MidiPitch,
+    pub key_pressure: KeyPressure,
+    pub controllers: Controllers,
+    pub voices: Option<VoiceAllocator>,
+    pub note_stack: NoteStack,
+    pub pedals: Pedals,
+    sysex_buffer: Vec<u8>, // sysex can span several events
+    pub midi_clock: MidiClock,
+    smoother: ParamSmoother,
+    block_start: Option<time::Timespec>, // set by process_events
+    analyzer: AudioAnalyzer,
+    spectrums: Vec<Option<SpectrumAnalyzer>>, // index is entry, built with the config
+    playing: bool, // host transport
+    host: HostCallback
 }
 
+const SYSEX_CAPACITY: usize = 1024;
+// kVstTransportPlaying in the time info flags
+const TRANSPORT_PLAYING: i32 = 1 << 1;
+
 const CC_TIMBRE: u8 = 74;
 const CC_PAN: u8 = 10;
+const CC_ALL_SOUND_OFF: u8 = 120;
+const CC_RESET_ALL_CONTROLLERS: u8 = 121;
+const CC_ALL_NOTES_OFF: u8 = 123;
+const CC_POLY_MODE_ON: u8 = 127;
 impl OscifyPlugin {
-    fn process_midi_event(&mut self, msg: &Message, time: (u32, u32)) {
+    fn entry_type(&self) -> config::EntryType {
+        self.entries.get(self.entry_index)
+            .map(|entry| entry.entry_type)
+            .unwrap_or(config::EntryType::Mono)
+    }
+
+    fn mono_config(&self) -> Option<MonoConfig> {
+        match self.entries.get(self.entry_index) {
+            Some(&config::Entry { entry_type: config::EntryType::Mono, mono, .. }) => Some(mono),
+            _ => None
+        }
+    }
+
+    fn user_param(&self, index: i32) -> Option<usize> {
+        if index < PARAM_USER { return None }
+        let index = (index - PARAM_USER) as usize;
+        if index < self.params.len() { Some(index) } else { None }
+    }
+
+    // the program mode goes after the user params so their indices stay put
+    fn program_param(&self) -> i32 {
+        PARAM_USER + self.params.len() as i32
+    }
+
+    // the entry parameter has one step per entry
+    fn entry_value(&self) -> f32 {
+        match self.entries.len() {
+            0 | 1 => 0.,
+            len => self.entry_index as f32 / (len - 1) as f32
+        }
+    }
+
+    fn entry_from_value(&self, value: f32) -> usize {
+        let last = self.entries.len().max(1) - 1;
+        ((value * last as f32).round() as usize).min(last)
+    }
+
+    fn select_entry(&mut self, index: usize, time: (u32, u32)) {
+        if index == self.entry_index { return }
+        // open notes belong to the old entry
+        self.release_all(time);
+        self.entry_index = index;
+        self.configure_entry();
+        // parameter names follow the entry
+        self.host.update_display();
+    }
+
+    fn configure_entry(&mut self) {
+        let entry = self.entries.get(self.entry_index);
+        // zones from a previous entry don't carry over
+        self.midi_pitch.reset_mpe();
+        if let Some(zones) = entry.and_then(|entry| entry.mpe) {
+            self.midi_pitch.configure_mpe(Zone::Lower, zones.lower);
+            self.midi_pitch.configure_mpe(Zone::Upper, zones.upper);
+        }
+        self.midi_pitch.set_tuning(entry.and_then(|entry| entry.tuning.clone()).unwrap_or_default());
+        self.voices = match entry {
+            Some(&config::Entry { entry_type: config::EntryType::Poly, voices: Some(count), steal, .. }) =>
+                Some(VoiceAllocator::new(count, steal)),
+            _ => None
+        };
+        // analyzers are built up front, switching entries doesn't allocate
+        if let Some(&mut Some(ref mut spectrum)) = self.spectrums.get_mut(self.entry_index) {
+            spectrum.reset();
+        }
+    }
+
+    // keys that channel messages are addressed to
+    fn channel_keys(&self, channel: u8) -> Vec<u8> {
+        match self.voices {
+            Some(ref voices) => voices.keys(channel),
+            None => vec![self.midi_pitch.get_key(channel)]
+        }
+    }
+
+    // keys sounding on a channel
+    fn notes(&self, channel: u8) -> Vec<u8> {
+        match self.voices {
+            Some(ref voices) => voices.keys(channel),
+            None => self.midi_pitch.get_note(channel).into_iter().collect()
+        }
+    }
+
+    fn process_pedals(&mut self, msg: &Message, time: (u32, u32)) -> bool {
         match msg {
             &Message::NoteOff { channel, key, velocity } =>
-                self.send_note(sender::NoteMessage {
-                    note_on: false,
-                    channel,
-                    key,
-                    velocity: u7_into_f32(velocity)
-                }, time),
+                return self.pedals.defer_note_off(channel, key, velocity),
+            &Message::NoteOn { channel, key, .. } =>
+                self.pedals.note_on(channel, key),
+            &Message::ControlChange { channel, controller, value } => {
+                if let Some(pedal) = Pedal::from_controller(controller) {
+                    let down = value >= 64;
+                    let should_send = self.entries.get(self.entry_index).map_or(false, |entry| entry.pedals);
+                    if should_send {
+                        for key in self.channel_keys(channel) {
+                            self.send_pedal(sender::PedalMessage { pedal, channel, key, down }, time)
+                        }
+                    }
+                    let mut released = self.pedals.set_pedal(channel, pedal, down);
+                    released.sort_by_key(|&(key, _)| self.is_mono_current(channel, key));
+                    for (key, velocity) in released {
+                        self.process_midi_event(&Message::NoteOff { channel, key, velocity }, time)
+                    }
+                }
+            },
+            _ => ()
+        }
+        false
+    }
+
+    // releases sort the sounding mono note last,
+    // so it doesn't fall back to a note that's about to be released
+    fn is_mono_current(&self, channel: u8, key: u8) -> bool {
+        self.mono_config()
+            .and_then(|mono| self.note_stack.current(mono.priority))
+            .map_or(false, |note| note.channel == channel && note.key == key)
+    }
+
+    // per-key pressure reduced to one value, for Mono entries
+    fn mono_pressure(&self) -> Option<u8> {
+        match self.entries.get(self.entry_index) {
+            Some(&config::Entry { entry_type: config::EntryType::Mono, key_pressure, .. }) =>
+                Some(self.key_pressure.reduce(key_pressure).unwrap_or(0)),
+            _ => None
+        }
+    }
+
+    fn process_midi_event(&mut self, msg: &Message, time: (u32, u32)) {
+        // note offs held by a pedal are sent when it's released
+        if self.process_pedals(msg, time) { return }
+        let pressure = self.mono_pressure();
+        match msg {
+            &Message::NoteOff { channel, key, velocity } => {
+                let mono = self.mono_config();
+                let previous = mono.and_then(|mono| self.note_stack.current(mono.priority));
+                self.note_stack.remove(channel, key);
+                if let Some(mono) = mono {
+                    let next = self.note_stack.current(mono.priority);
+                    self.send_mono_change(mono, previous, next, velocity, time);
+                } else {
+                    self.send_note(sender::NoteMessage::Off {
+                        channel,
+                        key,
+                        release_velocity: u7_into_f32(velocity)
+                    }, time);
+                }
+                if let Some(ref mut voices) = self.voices {
+                    voices.release(channel, key)
+                }
+            },
             &Message::NoteOn { channel, key, velocity } => {
-                if let Some(pitch) = self.midi_pitch.get_pending_pitch(channel, key) {
+                let mono = self.mono_config();
+                let previous = mono.and_then(|mono| self.note_stack.current(mono.priority));
+                self.note_stack.push(Note { channel, key, velocity });
+                if let Some(mono) = mono {
+                    let next = self.note_stack.current(mono.priority);
+                    self.send_mono_change(mono, previous, next, velocity, time);
+                } else {
+                    self.send_note_on(channel, key, velocity, time);
+                }
+            },
+            &Message::PitchBend { channel, value } => {
+                self.midi_pitch.set_bend(channel, value);
+                // master channel bend moves every note in the zone
+                let channels = match self.midi_pitch.zones.master_zone(channel) {
+                    Some(zone) => self.midi_pitch.zones.channels(zone),
+                    None => channel..(channel + 1)
+                };
+                match self.mono_config() {
+                    // the sounding note can be a fallback the pitch tracker has already released
+                    Some(mono) => if let Some(note) = self.note_stack.current(mono.priority) {
+                        if channels.clone().any(|c| c == note.channel) {
+                            self.send_pitch(note.channel, note.key, time)
+                        }
+                    },
+                    None => for channel in channels {
+                        for key in self.notes(channel) {
+                            self.send_pitch(channel, key, time)
+                        }
+                    }
+                }
+            },
+            &Message::KeyPressure { channel, key, pressure } => {
+                self.key_pressure.set_pressure(channel, key, pressure);
+                let pressure = match self.entry_type() {
+                    config::EntryType::Mono => self.mono_pressure(),
+                    _ => Some(pressure)
+                };
+                if let Some(pressure) = pressure {
                     self.send_channel(sender::ChannelMessage {
-                        channel_type: sender::ChannelType::Pitch,
+                        channel_type: sender::ChannelType::Pressure,
                         channel,
                         key,
-                        value: pitch
+                        value: u7_into_f32(pressure)
                     }, time)
-                } else { // no pitch bend, set pitch to as it is
+                }
+            },
+            &Message::ChannelPressure { channel, pressure } => {
+                for key in self.channel_keys(channel) {
                     self.send_channel(sender::ChannelMessage {
-                        channel_type: sender::ChannelType::Pitch,
+                        channel_type: sender::ChannelType::Pressure,
                         channel,
                         key,
-                        value: key as f32
+                        value: u7_into_f32(pressure)
                     }, time)
                 }
-                self.send_note(sender::NoteMessage {
-                    note_on: true,
-                    channel,
-                    key,
-                    velocity: u7_into_f32(velocity)
-                }, time)
             },
-            &Message::PitchBend { channel, value } => {
-                if let Some(pitch) = self.midi_pitch.get_pitch(channel, value) {
-                    let key = self.midi_pitch.get_key(channel);
+            &Message::ControlChange { channel, controller, value } => {
+                if let Some(event) = self.controllers.process_midi_event(&msg) {
+                    self.process_controller_event(event, time);
+                }
+                for key in self.channel_keys(channel) {
+                    self.process_control_change(channel, key, controller, value, time)
+                }
+            },
+            &Message::ProgramChange { program, .. } => {
+                let index = program as usize;
+                if self.program_mode.switches() && index < self.entries.len() {
+                    self.select_entry(index, time)
+                }
+                if self.program_mode.forwards() {
+                    self.send_program(program, time)
+                }
+            },
+            &Message::SysEx { ref data } => {
+                if self.midi_pitch.process_sysex(data) {
+                    // retune sounding notes
+                    for channel in 0..16 {
+                        for key in self.notes(channel) {
+                            self.send_pitch(channel, key, time)
+                        }
+                    }
+                }
+                let accepts = self.entries.get(self.entry_index)
+                    .and_then(|entry| entry.sysex.as_ref())
+                    .map_or(false, |sysex| sysex.accepts(midi::sysex_manufacturer(data)));
+                if accepts {
+                    self.send_sysex(data, time)
+                }
+            },
+            &Message::SysRealTime { .. } | &Message::SysCommon { .. } =>
+                for event in self.midi_clock.process_midi_event(&msg, into_seconds(time)) {
+                    self.send_transport(event, time)
+                },
+            &Message::ChannelMode { channel, controller, .. } =>
+                self.process_channel_mode(channel, controller, time)
+        }
+        self.midi_pitch.process_midi_event(&msg);
+        self.key_pressure.process_midi_event(&msg);
+
+        // a released key no longer counts towards Mono pressure
+        if let &Message::NoteOff { channel, key, .. } = msg {
+            match self.mono_pressure() {
+                Some(next) if Some(next) != pressure =>
                     self.send_channel(sender::ChannelMessage {
-                        channel_type: sender::ChannelType::Pitch,
+                        channel_type: sender::ChannelType::Pressure,
                         channel,
                         key,
-                        value: pitch
+                        value: u7_into_f32(next)
+                    }, time),
+                _ => ()
+            }
+        }
+    }
+
+    fn process_channel_mode(&mut self, channel: u8, controller: u8, time: (u32, u32)) {
+        match controller {
+            CC_RESET_ALL_CONTROLLERS => {
+                self.midi_pitch.reset_controllers(channel);
+                self.key_pressure.reset(channel);
+                self.controllers.reset(channel);
+                for key in self.notes(channel) {
+                    self.send_pitch(channel, key, time)
+                }
+                // Mono entries still count pressure held on other channels
+                let pressure = self.mono_pressure().unwrap_or(0);
+                for key in self.channel_keys(channel) {
+                    self.send_channel(sender::ChannelMessage {
+                        channel_type: sender::ChannelType::Pressure,
+                        channel,
+                        key,
+                        value: u7_into_f32(pressure)
                     }, time)
                 }
+                for (key, velocity) in self.pedals.reset(channel) {
+                    self.process_midi_event(&Message::NoteOff { channel, key, velocity }, time)
+                }
             },
-            &Message::ChannelPressure { channel, pressure } => {
-                let key = self.midi_pitch.get_key(channel);
+            // omni and mono/poly mode changes also turn all notes off
+            CC_ALL_SOUND_OFF | CC_ALL_NOTES_OFF...CC_POLY_MODE_ON => {
+                self.release_channel(channel, time);
+                self.send_panic(channel, time);
+            },
+            _ => ()
+        }
+    }
+
+    // sends note offs for every note sounding on a channel, including ones held by pedals
+    fn release_channel(&mut self, channel: u8, time: (u32, u32)) {
+        self.pedals.reset(channel);
+        let mut notes = self.note_stack.channel_notes(channel);
+        notes.sort_by_key(|note| self.is_mono_current(channel, note.key));
+        for note in notes {
+            self.process_midi_event(&Message::NoteOff { channel, key: note.key, velocity: 0 }, time)
+        }
+    }
+
+    fn release_all(&mut self, time: (u32, u32)) {
+        for channel in 0..16 {
+            self.release_channel(channel, time)
+        }
+    }
+
+    // stopping the transport ends the notes it was playing
+    fn process_transport(&mut self, playing: bool, time: (u32, u32)) {
+        if self.playing && !playing {
+            self.release_all(time);
+        }
+        self.playing = playing;
+    }
+
+    fn process_control_change(&mut self, channel: u8, key: u8, controller: u8, value: u8, time: (u32, u32)) {
+        let (is_mapped, is_high_res) = self.entries.get(self.entry_index)
+            .map_or((false, false), |entry| (
+                entry.control(sender::Controller::Cc(controller)).is_some(),
+                entry.is_high_res(controller)
+            ));
+        if is_high_res {
+            // already sent as a 14 bit value
+        } else if is_mapped {
+            self.send_control(sender::ControlMessage {
+                controller: sender::Controller::Cc(controller),
+                channel,
+                key,
+                value: value as f32 / 127.0
+            }, time)
+        } else {
+            let channel_type = match controller {
+                CC_TIMBRE => Some(sender::ChannelType::Timbre),
+                CC_PAN => Some(sender::ChannelType::Pan),
+                _ => None
+            };
+            if let Some(channel_type) = channel_type {
                 self.send_channel(sender::ChannelMessage {
-                    channel_type: sender::ChannelType::Pressure,
+                    channel_type,
                     channel,
                     key,
-                    value: u7_into_f32(pressure)
+                    value: u7_into_f32(value)
                 }, time)
-            },
-            &Message::ControlChange { channel, controller, value } => {
-                let key = self.midi_pitch.get_key(channel);
-                let value = u7_into_f32(value);
-                let channel_type = match controller {
-                    CC_TIMBRE => Some(sender::ChannelType::Timbre),
-                    CC_PAN => Some(sender::ChannelType::Pan),
-                    _ => None
-                };
-                if let Some(channel_type) = channel_type {
-                    self.send_channel(sender::ChannelMessage {
-                        channel_type,
-                        channel,
-                        key,
-                        value
+            }
+        }
+    }
+
+    fn send_note_on(&mut self, channel: u8, key: u8, velocity: u8, time: (u32, u32)) {
+        let allocation = self.voices.as_ref().map(|voices| voices.allocate(channel, key));
+        if let Some((id, stolen)) = allocation {
+            if let Some((channel, key)) = stolen {
+                self.send_note(sender::NoteMessage::Off {
+                    channel,
+                    key,
+                    release_velocity: 0.
+                }, time);
+            }
+            if let Some(ref mut voices) = self.voices {
+                voices.assign(id, channel, key, velocity)
+            }
+        }
+        self.send_pitch(channel, key, time);
+        self.send_note(sender::NoteMessage::On {
+            channel,
+            key,
+            velocity: u7_into_f32(velocity)
+        }, time)
+    }
+
+    // moves a mono entry from one note to another, either of which can be none
+    fn send_mono_change(&mut self, mono: MonoConfig, previous: Option<Note>, next: Option<Note>, velocity: u8, time: (u32, u32)) {
+        if previous == next { return }
+        match (previous, next) {
+            (Some(note), None) =>
+                self.send_note(sender::NoteMessage::Off {
+                    channel: note.channel,
+                    key: note.key,
+                    release_velocity: u7_into_f32(velocity)
+                }, time),
+            (previous, Some(note)) => {
+                let is_legato = previous.is_some();
+                self.send_glide(note.channel, note.key, if is_legato { mono.glide } else { 0. }, time);
+                self.send_pitch(note.channel, note.key, time);
+                if is_legato && mono.legato {
+                    self.send_key(note.channel, note.key, time)
+                } else {
+                    self.send_note(sender::NoteMessage::On {
+                        channel: note.channel,
+                        key: note.key,
+                        velocity: u7_into_f32(note.velocity)
                     }, time)
                 }
             },
-            _ => ()
+            (None, None) => ()
+        }
+    }
+
+    fn send_pitch(&mut self, channel: u8, key: u8, time: (u32, u32)) {
+        let pitch = self.midi_pitch.get_pitch(channel, key);
+        self.send_channel(sender::ChannelMessage {
+            channel_type: sender::ChannelType::Pitch,
+            channel,
+            key,
+            value: pitch
+        }, time)
+    }
+
+    fn process_controller_event(&mut self, event: ControllerEvent, time: (u32, u32)) {
+        let (controller, channel, value) = match event {
+            ControllerEvent::HighRes { channel, controller, value } =>
+                (sender::Controller::Cc14(controller), channel, value),
+            ControllerEvent::Param { channel, param: Param::Nrpn(param), value } =>
+                (sender::Controller::Nrpn(param), channel, value),
+            ControllerEvent::Param { channel, param: Param::Rpn(param), value } => {
+                self.midi_pitch.process_rpn(channel, param, value);
+                // tuning changes apply to notes that are already sounding
+                for channel in 0..16 {
+                    for key in self.notes(channel) {
+                        self.send_pitch(channel, key, time)
+                    }
+                }
+                return
+            }
+        };
+        for key in self.channel_keys(channel) {
+            self.send_control(sender::ControlMessage {
+                controller,
+                channel,
+                key,
+                value: u14_into_f32(value)
+            }, time)
+        }
+    }
+
+    fn process_sysex_payload(&mut self, payload: &[u8], time: (u32, u32)) {
+        if payload.first() == Some(&midi::SYSEX_START) {
+            self.sysex_buffer.clear();
+        } else if self.sysex_buffer.is_empty() {
+            error!("[{}] sysex continuation without start", self.osc_sender.id);
+            return
+        }
+        // the buffer doesn't grow on the audio thread
+        if self.sysex_buffer.len() + payload.len() > SYSEX_CAPACITY {
+            error!("[{}] sysex longer than {} bytes, discarding", self.osc_sender.id, SYSEX_CAPACITY);
+            self.sysex_buffer.clear();
+            return
+        }
+        self.sysex_buffer.extend_from_slice(payload);
+        if self.sysex_buffer.last() == Some(&midi::SYSEX_END) {
+            let data = self.sysex_buffer.clone();
+            self.sysex_buffer.clear();
+            self.process_midi_event(&Message::SysEx { data }, time);
         }
-        self.midi_pitch.process_midi_event(&msg);
     }
-    fn process_param_event(&mut self, index: usize, value: f32) {
-        self.send_param(sender::ParamMessage { param_index: index, value }, (0, 0));
+
+    fn process_params(&mut self, block: f32, block_start: time::Timespec) {
+        let changes = {
+            let entry = self.entries.get(self.entry_index);
+            let limits = |index: usize| entry
+                .and_then(|entry| entry.params.get(index))
+                .map_or(RateLimit::default(), |param| param.rate_limit());
+            self.smoother.process(&self.params, limits, block, into_seconds(get_time(block_start, 0.)))
+        };
+        for (index, value, offset) in changes {
+            self.process_param_event(index, value, get_time(block_start, offset as f64 * 1e9));
+        }
+    }
+
+    fn process_param_event(&mut self, index: usize, value: f32, time: (u32, u32)) {
+        self.send_param(sender::ParamMessage { param_index: index, value }, time);
     }
 }
 
@@ -124,47 +609,176 @@ impl Default for OscifyPlugin {
             vec![]
         });
 
+        let param_count = entries.iter().map(|entry| entry.params.len()).max().unwrap_or(0);
+        let mut params = vec![0.0; param_count];
+        // the host reads initial values as defaults
+        if let Some(entry) = entries.get(0) {
+            for (value, param) in params.iter_mut().zip(&entry.params) {
+                *value = param.default_value();
+            }
+        }
+
+        let spectrums = entries.iter()
+            .map(|entry| entry.spectrum.as_ref().map(SpectrumAnalyzer::new))
+            .collect();
+
         let osc_sender = osc::OscSender::new();
         if osc_sender.is_err() {
             error!("Couldn't connect")
         }
 
-        OscifyPlugin {
+        let mut plugin = OscifyPlugin {
             sample_rate: 0.,
             block_size: 0,
             osc_sender: osc_sender.unwrap(),
             entries,
             entry_index: 0,
             phase: 0.0,
-            params: [0.0; 8],
-            midi_pitch: MidiPitch::new()
+            program_mode: ProgramMode::Ignore,
+            smoother: ParamSmoother::new(&params),
+            block_start: None,
+            analyzer: AudioAnalyzer::new(),
+            spectrums,
+            params,
+            midi_pitch: MidiPitch::new(),
+            key_pressure: KeyPressure::new(),
+            controllers: Controllers::new(),
+            voices: None,
+            note_stack: NoteStack::new(),
+            pedals: Pedals::new(),
+            sysex_buffer: Vec::with_capacity(SYSEX_CAPACITY),
+            midi_clock: MidiClock::new(),
+            playing: false,
+            host: HostCallback::default()
+        };
+        plugin.configure_entry();
+        plugin
+    }
+}
+
+impl OscifyPlugin {
+    fn get_state(&self) -> State {
+        let entry = self.entries.get(self.entry_index);
+        let destination = self.osc_sender.destination();
+        State {
+            entry: entry.map(|entry| entry.name.clone()),
+            entry_index: self.entry_index,
+            phase: self.phase,
+            program_mode: self.program_mode,
+            params: self.params.iter().enumerate().map(|(i, &value)| ParamState {
+                name: entry.and_then(|entry| entry.params.get(i)).map(|param| param.name().to_string()),
+                value
+            }).collect(),
+            instance_id: Some(self.osc_sender.id),
+            destination: if destination == osc::default_destination() {
+                None
+            } else {
+                Some(destination.to_string())
+            },
+            ..State::new()
         }
     }
+
+    fn load_state(&mut self, state: State) {
+        let time = get_time(time::get_time(), 0.);
+
+        if let Some(id) = state.instance_id {
+            if let Err(err) = self.osc_sender.rebind(id) {
+                info!("[{}] Couldn't restore instance id {}: {}", self.osc_sender.id, id, err)
+            }
+        }
+        let destination = match state.destination {
+            Some(ref destination) => destination.parse().unwrap_or_else(|err| {
+                error!("[{}] Invalid destination {}: {}", self.osc_sender.id, destination, err);
+                osc::default_destination()
+            }),
+            None => osc::default_destination()
+        };
+        self.osc_sender.set_destination(destination);
+
+        let index = state.entry_index(&self.entries);
+        self.select_entry(index, time);
+        self.phase = state.phase;
+        self.program_mode = state.program_mode;
+        // sent with the next block
+        for (index, value) in state.param_indices(self.entries.get(self.entry_index)) {
+            if index < self.params.len() {
+                self.params[index] = value;
+            }
+        }
+        self.flush_midi_events();
+    }
+}
+
+impl Drop for OscifyPlugin {
+    fn drop(&mut self) {
+        self.release_all(get_time(time::get_time(), 0.));
+        self.flush_midi_events();
+    }
 }
 
 const PARAM_ENTRY: i32 = 0;
 const PARAM_PHASE: i32 = 1;
+const PARAM_USER: i32 = 2;
 
 impl plugin::Plugin for OscifyPlugin {
+    fn new(host: HostCallback) -> Self {
+        let mut plugin = OscifyPlugin::default();
+        plugin.host = host;
+        plugin
+    }
+
     fn get_info(&self) -> plugin::Info {
         plugin::Info {
             name: "Oscify".to_string(),
             vendor: "delu".to_string(),
             unique_id: 9002,
             category: plugin::Category::Analysis,
-            inputs: 0,
-            outputs: 0,
-            parameters: 10,
+            inputs: CHANNELS as i32,
+            outputs: CHANNELS as i32,
+            parameters: self.program_param() + 1,
+            preset_chunks: true,
             ..plugin::Info::default()
         }
     }
 
+    fn suspend(&mut self) {
+        debug!("[{}] suspend", self.osc_sender.id);
+        self.release_all(get_time(time::get_time(), 0.));
+        self.flush_midi_events();
+    }
+
+    fn resume(&mut self) {
+        debug!("[{}] resume", self.osc_sender.id);
+    }
+
+    // a single program, so banks and presets are the same
+    fn get_preset_data(&mut self) -> Vec<u8> {
+        state::encode(&self.get_state())
+    }
+
+    fn get_bank_data(&mut self) -> Vec<u8> {
+        self.get_preset_data()
+    }
+
+    fn load_preset_data(&mut self, data: &[u8]) {
+        match state::decode(data) {
+            Ok(state) => self.load_state(state),
+            Err(err) => error!("[{}] Couldn't load state: {}", self.osc_sender.id, err)
+        }
+    }
+
+    fn load_bank_data(&mut self, data: &[u8]) {
+        self.load_preset_data(data)
+    }
+
     fn set_sample_rate(&mut self, rate: f32) { self.sample_rate = rate; }
 
     fn set_block_size(&mut self, size: i64) { self.block_size = size; }
 
     fn process_events(&mut self, events: &api::Events) {
         let current_time = time::get_time();
+        self.block_start = Some(current_time);
         debug!("Received {} events:", events.num_events);
         for &e in events.events_raw() {
             let event: Event = Event::from(unsafe { *e });
@@ -177,9 +791,57 @@ impl plugin::Plugin for OscifyPlugin {
                     } else {
                         error!("[{}] invalid midi: {:?}", self.osc_sender.id, ev.data)
                     },
+                Event::SysEx(ev) => {
+                    let t = get_time(current_time, (ev.delta_frames as f64) / (self.sample_rate as f64) * 1e9);
+                    self.process_sysex_payload(ev.payload, t);
+                },
                 _ => debug!("[{}] non-midi event", self.osc_sender.id)
             }
         }
+    }
+
+    // midi from process_events, levels and params go out together, once per block
+    fn process(&mut self, buffer: AudioBuffer<f32>) {
+        let block = if self.sample_rate > 0. { buffer.samples() as f32 / self.sample_rate } else { 0. };
+        // params share the time base of the block's midi
+        let block_start = self.block_start.take().unwrap_or_else(time::get_time);
+        let time = get_time(block_start, 0.);
+
+        // hosts without time info never stop
+        let playing = self.host.get_time_info(0)
+            .map_or(self.playing, |info| info.flags & TRANSPORT_PLAYING != 0);
+        self.process_transport(playing, time);
+
+        let (inputs, mut outputs) = buffer.split();
+        // audio passes through
+        for (input, output) in inputs.iter().zip(outputs.iter_mut()) {
+            output.copy_from_slice(input);
+        }
+
+        let analysis = self.entries.get(self.entry_index).and_then(|entry| entry.analysis);
+        if let Some(config) = analysis {
+            for (channel, input) in inputs.iter().enumerate().take(CHANNELS) {
+                self.analyzer.process(channel, input, &config, self.sample_rate);
+            }
+            if let Some(levels) = self.analyzer.take(block, config.interval) {
+                for (channel, &levels) in levels.iter().enumerate() {
+                    self.send_levels(channel, levels, time);
+                }
+            }
+        }
+
+        // taken out while it's in use so the bands can be sent without a copy
+        let spectrum = self.spectrums.get_mut(self.entry_index).and_then(Option::take);
+        if let Some(mut spectrum) = spectrum {
+            spectrum.process(&inputs, self.sample_rate);
+            let format = spectrum.format();
+            if let Some(bands) = spectrum.take(block) {
+                self.send_spectrum(bands, format, time);
+            }
+            self.spectrums[self.entry_index] = Some(spectrum);
+        }
+
+        self.process_params(block, block_start);
         self.flush_midi_events();
     }
 
@@ -192,52 +854,246 @@ impl plugin::Plugin for OscifyPlugin {
         match index {
             PARAM_ENTRY => "Entry".to_string(),
             PARAM_PHASE => "Phase".to_string(),
-            2...9 => {
-                let index = index as usize - 2;
-                self.entries.get(self.entry_index)
-                    .and_then(|entry| entry.params.get(index as usize) )
-                    .map(|s| s.clone())
-                    .unwrap_or_else(|| format!("Param {}", index))
-            },
+            index if index == self.program_param() => "Program".to_string(),
+            _ => match self.user_param(index) {
+                // slots past the active entry's params are unused
+                Some(index) => self.entries.get(self.entry_index)
+                    .and_then(|entry| entry.params.get(index))
+                    .map(|param| param.name().to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                None => "".to_string()
+            }
+        }
+    }
+
+    fn get_parameter_label(&self, index: i32) -> String {
+        match (self.user_param(index), self.entries.get(self.entry_index)) {
+            (Some(index), Some(entry)) =>
+                entry.params.get(index).map_or("", |param| param.unit()).to_string(),
             _ => "".to_string()
         }
     }
 
     fn get_parameter_text(&self, index: i32) -> String {
         match index {
-            PARAM_ENTRY => {
-                if let Some(entry) = self.entries.get(self.entry_index) {
-                    format!("{}: {}", self.entry_index, entry.name)
-                } else {
-                    format!("{}: undefined", self.entry_index)
-                }
+            PARAM_ENTRY => match self.entries.get(self.entry_index) {
+                Some(entry) => format!("{}: {}", self.entry_index, entry.name),
+                None => "No entries".to_string()
             },
             PARAM_PHASE => format!("{:.0}˚", 360.0 * self.phase),
-            2...9 => self.params[index as usize - 2].to_string(),
-            _ => "".to_string()
+            index if index == self.program_param() => self.program_mode.name().to_string(),
+            _ => match self.user_param(index) {
+                Some(index) => match self.entries.get(self.entry_index) {
+                    Some(entry) => format!("{:.2}", entry.param_value(index, self.params[index])),
+                    None => self.params[index].to_string()
+                },
+                None => "".to_string()
+            }
         }
     }
 
     fn get_parameter(&self, index: i32) -> f32 {
         match index {
-            PARAM_ENTRY => self.entry_index as f32 / 100.0,
+            PARAM_ENTRY => self.entry_value(),
             PARAM_PHASE => self.phase,
-            2...9 => self.params[index as usize - 2],
-            _ => 0.0
+            index if index == self.program_param() => {
+                let index = PROGRAM_MODES.iter().position(|&mode| mode == self.program_mode).unwrap_or(0);
+                index as f32 / (PROGRAM_MODES.len() - 1) as f32
+            },
+            _ => self.user_param(index).map_or(0.0, |index| self.params[index])
         }
     }
 
     fn set_parameter(&mut self, index: i32, value: f32) {
         match index {
-            PARAM_ENTRY => self.entry_index = (value * 100.0) as usize,
+            PARAM_ENTRY => {
+                let index = self.entry_from_value(value);
+                self.select_entry(index, get_time(time::get_time(), 0.));
+                self.flush_midi_events();
+            },
             PARAM_PHASE => self.phase = value,
-            2...9 => {
-                let index = index as usize - 2;
+            index if index == self.program_param() => {
+                let index = (value * (PROGRAM_MODES.len() - 1) as f32).round() as usize;
+                self.program_mode = PROGRAM_MODES[index.min(PROGRAM_MODES.len() - 1)];
+            },
+            // sent with the next block
+            _ => if let Some(index) = self.user_param(index) {
                 self.params[index] = value;
-                self.process_param_event(index, value);
-                self.flush_midi_events();
+            }
+        }
+    }
+
+    fn string_to_parameter(&mut self, index: i32, text: String) -> bool {
+        let text = text.trim();
+        match index {
+            PARAM_ENTRY => {
+                // by name, or by index
+                let len = self.entries.len();
+                let by_name = self.entries.iter().position(|entry| entry.name == text);
+                let by_index = text.parse().ok().and_then(|i: usize| if i < len { Some(i) } else { None });
+                match by_name.or(by_index) {
+                    Some(index) => {
+                        self.select_entry(index, get_time(time::get_time(), 0.));
+                        self.flush_midi_events();
+                        true
+                    },
+                    None => false
+                }
             },
-            _ => ()
+            index if index == self.program_param() => match PROGRAM_MODES.iter().find(|mode| mode.name() == text) {
+                Some(&mode) => {
+                    self.program_mode = mode;
+                    true
+                },
+                None => false
+            },
+            _ => {
+                // in output units, the unit is optional
+                let param = match (self.user_param(index), self.entries.get(self.entry_index)) {
+                    (Some(index), Some(entry)) => {
+                        let unit = entry.params.get(index).map_or("", |param| param.unit());
+                        let text = if !unit.is_empty() && text.ends_with(unit) {
+                            text[..text.len() - unit.len()].trim()
+                        } else {
+                            text
+                        };
+                        text.parse::<f32>().ok().map(|value| (index, entry.param_normalize(index, value)))
+                    },
+                    _ => None
+                };
+                match param {
+                    Some((index, value)) => {
+                        self.params[index] = value;
+                        true
+                    },
+                    None => false
+                }
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{UdpSocket, SocketAddr};
+    use std::time::Duration;
+    use vst2::plugin::Plugin;
+    use rosc::{decoder, OscPacket, OscType};
+
+    const CONFIG: &str = r#"[{
+        "type": "Poly",
+        "name": "Test",
+        "address": "test",
+        "params": []
+    }]"#;
+
+    fn plugin() -> (OscifyPlugin, UdpSocket) {
+        let mut plugin = OscifyPlugin::default();
+        plugin.entries = config::parse(CONFIG).unwrap();
+        plugin.configure_entry();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        if let SocketAddr::V4(addr) = sock.local_addr().unwrap() {
+            plugin.osc_sender.set_destination(addr);
+        }
+        (plugin, sock)
+    }
+
+    // Poly entries without voices address notes by channel
+    fn note_on(plugin: &mut OscifyPlugin, channel: u8, time: (u32, u32)) {
+        plugin.process_midi_event(&Message::NoteOn { channel, key: 60, velocity: 100 }, time);
+    }
+
+    fn flatten(packet: OscPacket, messages: &mut Vec<(String, Vec<OscType>)>) {
+        match packet {
+            OscPacket::Message(msg) => messages.push((msg.addr, msg.args.unwrap_or_default())),
+            OscPacket::Bundle(bundle) => for packet in bundle.content {
+                flatten(packet, messages)
+            }
+        }
+    }
+
+    // the messages of one flush, in order
+    fn received(sock: &UdpSocket) -> Vec<(String, Vec<OscType>)> {
+        let mut buf = [0u8; 4096];
+        let size = sock.recv(&mut buf).unwrap();
+        let mut messages = vec![];
+        flatten(decoder::decode(&buf[..size]).unwrap(), &mut messages);
+        messages
+    }
+
+    // the note on/off values of one flush, in order
+    fn received_notes(sock: &UdpSocket) -> Vec<(String, bool)> {
+        received(sock).into_iter().filter_map(|(addr, args)| match args.get(0) {
+            Some(&OscType::Bool(on)) if addr.ends_with("/note/on") => Some((addr, on)),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn offset_carries_into_seconds() {
+        let (sec, frac) = get_time(time::Timespec::new(0, 999_999_999), 1e6);
+        assert_eq!(sec, EPOCH_DELTA as u32 + 1);
+        assert!(into_seconds((sec, frac)) - into_seconds((EPOCH_DELTA as u32 + 1, 0)) < 0.0011);
+    }
+
+    #[test]
+    fn suspend_releases_notes() {
+        let (mut plugin, sock) = plugin();
+        let time = get_time(time::get_time(), 0.);
+        note_on(&mut plugin, 1, time);
+        note_on(&mut plugin, 2, time);
+        plugin.suspend();
+        assert_eq!(received_notes(&sock), vec![
+            ("/oscify/test/1/note/on".to_string(), true),
+            ("/oscify/test/2/note/on".to_string(), true),
+            ("/oscify/test/1/note/on".to_string(), false),
+            ("/oscify/test/2/note/on".to_string(), false)
+        ]);
+        // nothing is left to release
+        assert!(plugin.note_stack.channel_notes(1).is_empty());
+    }
+
+    #[test]
+    fn drop_releases_notes() {
+        let (mut plugin, sock) = plugin();
+        note_on(&mut plugin, 1, get_time(time::get_time(), 0.));
+        drop(plugin);
+        assert_eq!(received_notes(&sock), vec![
+            ("/oscify/test/1/note/on".to_string(), true),
+            ("/oscify/test/1/note/on".to_string(), false)
+        ]);
+    }
+
+    #[test]
+    fn transport_stop_releases_notes() {
+        let (mut plugin, sock) = plugin();
+        let time = get_time(time::get_time(), 0.);
+        plugin.process_transport(true, time);
+        note_on(&mut plugin, 1, time);
+        plugin.process_transport(true, time);
+        plugin.process_transport(false, time);
+        plugin.flush_midi_events();
+        assert_eq!(received_notes(&sock), vec![
+            ("/oscify/test/1/note/on".to_string(), true),
+            ("/oscify/test/1/note/on".to_string(), false)
+        ]);
+    }
+
+    #[test]
+    fn reset_all_controllers_clears_pressure() {
+        let (mut plugin, sock) = plugin();
+        let time = get_time(time::get_time(), 0.);
+        note_on(&mut plugin, 1, time);
+        plugin.process_midi_event(&Message::KeyPressure { channel: 1, key: 60, pressure: 100 }, time);
+        plugin.process_midi_event(&Message::ChannelMode { channel: 1, controller: 121, value: 0 }, time);
+        plugin.flush_midi_events();
+        let pressures: Vec<_> = received(&sock).into_iter()
+            .filter(|&(ref addr, _)| addr.ends_with("/press"))
+            .map(|(_, args)| args)
+            .collect();
+        assert_eq!(pressures.len(), 2);
+        assert_eq!(pressures.last(), Some(&vec![OscType::Float(0.)]));
+    }
+}